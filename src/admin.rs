@@ -0,0 +1,418 @@
+use crate::grid::CellData;
+use crate::tokendb::{Role, Token, UserStatus};
+use crate::{rollback, session, timestamp_from_secs, GlobalState};
+use anyhow::{anyhow, Result};
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Catcher, FromForm, Route, State};
+use std::fmt::Write;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+
+// UID that actions authenticated with admin_key are attributed to in the audit log
+const ADMIN_KEY_UID: &str = "admin_key";
+
+type AdminResult = Result<String, (Status, String)>;
+
+// Request guard for the admin API. Callers authenticate either with "Authorization: Bearer
+// <token>", where the token is a user's token or admin_key, or with a session cookie. Only users
+// that have a role get through
+pub struct Actor {
+    uid: String,
+    role: Role,
+}
+
+// Why the guard failed, for the catchers
+struct AuthError(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let state = match request.guard::<&State<&'static GlobalState>>().await {
+            Outcome::Success(state) => *state.inner(),
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        match authenticate(state, request).await {
+            Ok(actor) => Outcome::Success(actor),
+            Err((status, message)) => {
+                request.local_cache(|| AuthError(message));
+                Outcome::Error((status, ()))
+            }
+        }
+    }
+}
+
+async fn authenticate(
+    state: &GlobalState,
+    request: &Request<'_>,
+) -> Result<Actor, (Status, String)> {
+    let unauthorized = |message: String| (Status::Unauthorized, message);
+
+    let uid = match request.headers().get_one("Authorization") {
+        Some(header) => {
+            let token = header
+                .strip_prefix("Bearer ")
+                .ok_or_else(|| {
+                    unauthorized("Expected 'Authorization: Bearer <token>'".to_string())
+                })?
+                .trim();
+            if let Some(ref admin_key) = state.config().admin_key {
+                if bool::from(admin_key.as_bytes().ct_eq(token.as_bytes())) {
+                    return Ok(Actor {
+                        uid: ADMIN_KEY_UID.to_string(),
+                        role: Role::Admin,
+                    });
+                }
+            }
+            state
+                .tokendb
+                .uid_of_token(&Token::from_string(token))
+                .map_err(|e| unauthorized(e.to_string()))?
+                .ok_or_else(|| unauthorized("This token does not exist".to_string()))?
+        }
+        None => match request.guard::<session::Session>().await {
+            Outcome::Success(session) => session
+                .uid()
+                .map_err(|e| unauthorized(e.to_string()))?
+                .to_string(),
+            _ => {
+                return Err(unauthorized(
+                    "Log in or pass a token in the Authorization header".to_string(),
+                ))
+            }
+        },
+    };
+
+    let forbidden = |message: String| (Status::Forbidden, message);
+    let internal = |e: anyhow::Error| (Status::InternalServerError, e.to_string());
+
+    if let UserStatus::Banned(_) = state.tokendb.user_status(&uid).map_err(internal)? {
+        return Err(forbidden("You are banned".to_string()));
+    }
+    let role = state.tokendb.role(&uid).map_err(internal)?.ok_or_else(|| {
+        forbidden(format!(
+            "User {} does not have access to the admin API",
+            uid
+        ))
+    })?;
+
+    Ok(Actor { uid, role })
+}
+
+impl Actor {
+    fn require(&self, role: Role) -> Result<(), (Status, String)> {
+        if self.role < role {
+            return Err((
+                Status::Forbidden,
+                format!("This action requires the {} role", role.name()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Writes the action and its outcome to the audit log and turns the outcome into a response
+async fn audit(
+    state: &GlobalState,
+    actor: &Actor,
+    action: &str,
+    details: String,
+    result: Result<String>,
+) -> AdminResult {
+    let outcome = match result {
+        Ok(ref message) => message.clone(),
+        Err(ref e) => format!("failed: {}", e),
+    };
+    if let Err(e) = state
+        .audit
+        .lock()
+        .await
+        .record(&actor.uid, action, &details, &outcome)
+    {
+        eprintln!("{:?}", e);
+    }
+    result.map_err(|e| (Status::BadRequest, e.to_string()))
+}
+
+#[derive(FromForm)]
+struct UserStatusForm<'r> {
+    uid: &'r str,
+    status: &'r str,
+    until: Option<u64>,
+}
+
+#[derive(FromForm)]
+struct RollbackForm<'r> {
+    uid: Option<&'r str>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+#[derive(FromForm)]
+struct WipeForm<'r> {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    color: Option<&'r str>,
+}
+
+#[derive(FromForm)]
+struct CooldownForm {
+    seconds: f64,
+}
+
+#[derive(FromForm)]
+struct ResizeForm {
+    width: u32,
+    height: u32,
+    force: Option<bool>,
+}
+
+#[derive(FromForm)]
+struct RoleForm<'r> {
+    uid: &'r str,
+    role: &'r str,
+}
+
+#[derive(FromForm)]
+struct TokenForm<'r> {
+    uid: &'r str,
+    token: Option<&'r str>,
+}
+
+#[rocket::get("/users")]
+fn users(state: &State<&'static GlobalState>, actor: Actor) -> AdminResult {
+    actor.require(Role::Moderator)?;
+    let tokens = state
+        .tokendb
+        .list_tokens()
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+
+    let mut out = String::new();
+    for info in tokens {
+        let last_use = match info.last_use {
+            Some(last_use) => last_use
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis())
+                .to_string(),
+            None => "never".to_string(),
+        };
+        let role = info.role.map_or("none", |role| role.name());
+        writeln!(out, "{} {} {} {}", info.uid, last_use, info.status, role).unwrap();
+    }
+    Ok(out)
+}
+
+#[rocket::post("/user_status", data = "<info>")]
+async fn user_status(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<UserStatusForm<'_>>,
+) -> AdminResult {
+    actor.require(Role::Moderator)?;
+    let result =
+        UserStatus::parse(info.status, info.until.map(timestamp_from_secs)).and_then(|status| {
+            state.tokendb.set_user_status(info.uid, status)?;
+            Ok(format!("User {} is now {}", info.uid, status))
+        });
+    let details = format!(
+        "uid={} status={} until={:?}",
+        info.uid, info.status, info.until
+    );
+    audit(state, &actor, "user_status", details, result).await
+}
+
+#[rocket::post("/rollback", data = "<info>")]
+async fn rollback_cells(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<RollbackForm<'_>>,
+) -> AdminResult {
+    actor.require(Role::Moderator)?;
+    let filter = rollback::Filter {
+        uid: info.uid.map(|uid| uid.to_string()),
+        since: info.since.map(timestamp_from_secs),
+        until: info.until.map(timestamp_from_secs),
+    };
+    let result = if filter.is_empty() {
+        Err(anyhow!(
+            "At least one of 'uid', 'since', and 'until' must be set"
+        ))
+    } else {
+        state
+            .rollback(&filter)
+            .await
+            .map(|count| format!("Restored {} cells", count))
+    };
+    let details = format!(
+        "uid={:?} since={:?} until={:?}",
+        info.uid, info.since, info.until
+    );
+    audit(state, &actor, "rollback", details, result).await
+}
+
+#[rocket::post("/wipe", data = "<info>")]
+async fn wipe(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<WipeForm<'_>>,
+) -> AdminResult {
+    actor.require(Role::Moderator)?;
+    let result = async {
+        let color = info.color.map(CellData::parse).transpose()?;
+        let count = state
+            .wipe(info.x, info.y, info.width, info.height, color)
+            .await?;
+        Ok(format!("Wiped {} cells", count))
+    }
+    .await;
+    let details = format!(
+        "x={} y={} width={} height={} color={:?}",
+        info.x, info.y, info.width, info.height, info.color
+    );
+    audit(state, &actor, "wipe", details, result).await
+}
+
+#[rocket::post("/cooldown", data = "<info>")]
+async fn cooldown(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<CooldownForm>,
+) -> AdminResult {
+    actor.require(Role::Admin)?;
+    let result = state
+        .set_cooldown(info.seconds)
+        .map(|()| format!("Cooldown is now {} seconds", info.seconds));
+    let details = format!("seconds={}", info.seconds);
+    audit(state, &actor, "cooldown", details, result).await
+}
+
+#[rocket::post("/resize", data = "<info>")]
+async fn resize(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<ResizeForm>,
+) -> AdminResult {
+    actor.require(Role::Admin)?;
+    let force = info.force.unwrap_or(false);
+    let result = state
+        .resize(info.width, info.height, force)
+        .await
+        .map(|()| format!("Resized the grid to {}x{}", info.width, info.height));
+    let details = format!(
+        "width={} height={} force={}",
+        info.width, info.height, force
+    );
+    audit(state, &actor, "resize", details, result).await
+}
+
+#[rocket::post("/role", data = "<info>")]
+async fn role(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<RoleForm<'_>>,
+) -> AdminResult {
+    actor.require(Role::Admin)?;
+    let result = parse_role(info.role).and_then(|role| {
+        state.tokendb.set_role(info.uid, role)?;
+        Ok(format!("User {} now has role {}", info.uid, info.role))
+    });
+    let details = format!("uid={} role={}", info.uid, info.role);
+    audit(state, &actor, "role", details, result).await
+}
+
+// Issues a token for a user, either the given one or a random one
+#[rocket::post("/add_token", data = "<info>")]
+async fn add_token(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<TokenForm<'_>>,
+) -> AdminResult {
+    actor.require(Role::Admin)?;
+    let result = match info.token {
+        Some(token) => state.tokendb.add_token(Token::from_string(token), info.uid),
+        None => state
+            .tokendb
+            .create_token_for_user(info.uid, state.config().token_bytes),
+    };
+    // The token itself must not end up in the log
+    let details = format!("uid={}", info.uid);
+    let token = match result {
+        Ok(token) => token,
+        Err(e) => return audit(state, &actor, "add_token", details, Err(e)).await,
+    };
+    audit(
+        state,
+        &actor,
+        "add_token",
+        details,
+        Ok("Created a token".to_string()),
+    )
+    .await?;
+    Ok(format!("Token of user {}: {}", info.uid, token))
+}
+
+#[rocket::post("/rotate_token", data = "<info>")]
+async fn rotate_token(
+    state: &State<&'static GlobalState>,
+    actor: Actor,
+    info: Form<TokenForm<'_>>,
+) -> AdminResult {
+    actor.require(Role::Admin)?;
+    let result = state
+        .tokendb
+        .rotate_token_for_user(info.uid, state.config().token_bytes);
+    let details = format!("uid={}", info.uid);
+    let token = match result {
+        Ok(token) => token,
+        Err(e) => return audit(state, &actor, "rotate_token", details, Err(e)).await,
+    };
+    audit(
+        state,
+        &actor,
+        "rotate_token",
+        details,
+        Ok("Rotated the token".to_string()),
+    )
+    .await?;
+    Ok(format!("New token of user {}: {}", info.uid, token))
+}
+
+#[rocket::catch(401)]
+fn unauthorized(request: &Request<'_>) -> String {
+    request.local_cache(|| AuthError(String::new())).0.clone()
+}
+
+#[rocket::catch(403)]
+fn forbidden(request: &Request<'_>) -> String {
+    request.local_cache(|| AuthError(String::new())).0.clone()
+}
+
+fn parse_role(name: &str) -> Result<Option<Role>> {
+    match name {
+        "none" => Ok(None),
+        _ => Ok(Some(Role::from_name(name)?)),
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    rocket::routes![
+        users,
+        user_status,
+        rollback_cells,
+        wipe,
+        cooldown,
+        resize,
+        role,
+        add_token,
+        rotate_token
+    ]
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    rocket::catchers![unauthorized, forbidden]
+}
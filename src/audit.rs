@@ -0,0 +1,36 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Text log of admin actions, one "<unix_time_ms> <uid> <action> <details>: <outcome>" line per
+// action
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<AuditLog> {
+        let file = File::options()
+            .append(true)
+            .create(true)
+            .open(path)
+            .context("Failed to open audit log")?;
+        Ok(AuditLog { file })
+    }
+
+    pub fn record(&mut self, uid: &str, action: &str, details: &str, outcome: &str) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis();
+        // Newlines would let the details forge log lines
+        let line =
+            format!("{} {} {} {}: {}", timestamp, uid, action, details, outcome).replace('\n', " ");
+        self.file
+            .write_all(format!("{}\n", line).as_bytes())
+            .context("Failed to append to audit log")?;
+        self.file.sync_data().context("Failed to sync audit log")
+    }
+}
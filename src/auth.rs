@@ -0,0 +1,114 @@
+use crate::config::AuthConfig;
+use crate::ejudge::EjudgeProvider;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+pub struct Credentials<'a> {
+    pub login: &'a str,
+    pub password: &'a str,
+    // Only used by providers that need to know where to look the user up, like ejudge
+    pub group: Option<usize>,
+}
+
+#[rocket::async_trait]
+pub trait AuthProvider: Send + Sync {
+    // Returns the UID of the user, namespaced by the provider, e.g. "ejudge/<login>", or None if
+    // the credentials are invalid
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<String>>;
+}
+
+// Accepts logins and passwords listed in a file, one "<login> <password>" pair per line. Empty
+// lines and lines starting with '#' are ignored
+pub struct StaticProvider {
+    passwords: HashMap<String, String>,
+}
+
+// Forwards the credentials to an external service as a POST form with 'login', 'password', and
+// 'group' fields. A 2xx response means the credentials are valid, 401 and 403 mean they are not
+pub struct HttpProvider {
+    url: String,
+}
+
+pub fn from_config(config: &AuthConfig, dir_path: &Path) -> Result<Arc<dyn AuthProvider>> {
+    Ok(match config {
+        AuthConfig::Ejudge { url, contests } => Arc::new(EjudgeProvider {
+            url: url.clone(),
+            contests: contests.clone(),
+        }),
+        AuthConfig::Static { file } => Arc::new(StaticProvider::load(&dir_path.join(file))?),
+        AuthConfig::Http { url } => Arc::new(HttpProvider { url: url.clone() }),
+    })
+}
+
+impl StaticProvider {
+    fn load(path: &Path) -> Result<StaticProvider> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read credentials file {:?}", path))?;
+        let mut passwords = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((login, password)) => {
+                    passwords.insert(login.to_string(), password.trim_start().to_string());
+                }
+                None => bail!(
+                    "Invalid credentials file {:?}: line {} must be '<login> <password>'",
+                    path,
+                    i + 1
+                ),
+            }
+        }
+        Ok(StaticProvider { passwords })
+    }
+}
+
+#[rocket::async_trait]
+impl AuthProvider for StaticProvider {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<String>> {
+        match self.passwords.get(credentials.login) {
+            Some(password)
+                if bool::from(password.as_bytes().ct_eq(credentials.password.as_bytes())) =>
+            {
+                Ok(Some(format!("static/{}", credentials.login)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl AuthProvider for HttpProvider {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<String>> {
+        let res = reqwest::Client::new()
+            .post(&self.url)
+            .form(&[
+                ("login", credentials.login.to_string()),
+                ("password", credentials.password.to_string()),
+                (
+                    "group",
+                    credentials
+                        .group
+                        .map_or(String::new(), |group| group.to_string()),
+                ),
+            ])
+            .send()
+            .await
+            .context("Failed to reach the authentication service")?;
+        let status = res.status();
+        if status.is_success() {
+            Ok(Some(format!("http/{}", credentials.login)))
+        } else if status == reqwest::StatusCode::UNAUTHORIZED
+            || status == reqwest::StatusCode::FORBIDDEN
+        {
+            Ok(None)
+        } else {
+            bail!("Authentication service responded with {}", status);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::session;
+use crate::snapshot::{DAY, HOUR};
+use anyhow::{bail, Context, Result};
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Config {
+    // Minimal interval between two placements of a single user
+    pub cooldown_seconds: f64,
+    // Address and port of the HTTP server. If unset, Rocket's own configuration is used
+    pub address: Option<IpAddr>,
+    pub port: Option<u16>,
+    // Address of the standalone websocket server, e.g. "0.0.0.0:9000". If unset, websockets are
+    // only served at /ws by the HTTP server
+    pub standalone_ws_address: Option<String>,
+    pub static_dir: String,
+    // Bearer token that gives admin access to /admin/*, see admin.rs. Unset by default
+    pub admin_key: Option<String>,
+    // Key that session cookies are encrypted with, 256 bits in hex or base64. If unset, a random
+    // key is generated and kept in the data directory
+    pub secret_key: Option<String>,
+    // Session cookies set by /get_token are valid for this long
+    pub session_lifetime_hours: u64,
+    // Number of random bytes in newly issued tokens
+    pub token_bytes: usize,
+    pub auth: AuthConfig,
+    // OpenID Connect login at /oidc/login, in addition to the password form. Disabled if unset
+    pub oidc: Option<OidcConfig>,
+    // Rectangles of the grid that can't be written to or seen, see regions.rs. Later regions
+    // take precedence over earlier ones
+    pub regions: Vec<RegionConfig>,
+    // Periodic copies of the grid in <data_dir>/snapshots, see snapshot.rs. Disabled if unset
+    pub snapshots: Option<SnapshotConfig>,
+}
+
+// Where /get_token checks the credentials. Uids are namespaced by the provider
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", tag = "provider", rename_all = "lowercase")]
+pub enum AuthConfig {
+    // Checks the credentials against ejudge contests. The group selected on the form is an index
+    // into the list of contests, starting from 1
+    Ejudge { url: String, contests: Vec<u32> },
+    // Reads "<login> <password>" pairs from a file, relative to the data directory
+    Static { file: String },
+    // Asks an external service, see auth::HttpProvider
+    Http { url: String },
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OidcConfig {
+    // Issuer URL; the provider metadata is discovered at <issuer>/.well-known/openid-configuration
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // Must point to /oidc/callback of this server and be registered with the provider
+    pub redirect_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RegionConfig {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub state: RegionState,
+    // UNIX timestamp at which a hidden region opens. If unset, it stays hidden
+    pub reveal_at: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum RegionState {
+    Open,
+    // Read-only, e.g. a finished logo
+    Locked,
+    // Read-only and not shown to the clients
+    Hidden,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SnapshotConfig {
+    pub interval_minutes: u64,
+    // Snapshots younger than this are thinned out to one per hour, older ones to one per day
+    pub keep_hourly_hours: u64,
+    // Snapshots older than this are deleted. If unset, daily snapshots are kept forever
+    pub keep_daily_days: Option<u64>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> SnapshotConfig {
+        SnapshotConfig {
+            interval_minutes: 60,
+            keep_hourly_hours: 24,
+            keep_daily_days: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            cooldown_seconds: 10.0,
+            address: None,
+            port: None,
+            standalone_ws_address: None,
+            static_dir: "static".to_string(),
+            admin_key: None,
+            secret_key: None,
+            session_lifetime_hours: 24 * 7,
+            token_bytes: 16,
+            auth: AuthConfig::Ejudge {
+                url: "https://ejudge.algocode.ru/cgi-bin/new-client".to_string(),
+                contests: vec![31027, 32030, 33030, 34030, 35025],
+            },
+            oidc: None,
+            regions: Vec::new(),
+            snapshots: None,
+        }
+    }
+}
+
+impl Config {
+    // Reads rplace.toml from the data directory, if it exists, and applies overrides from
+    // RPLACE_* environment variables on top. Nested keys are separated with a double underscore,
+    // e.g. RPLACE_AUTH__CONTESTS=[1,2,3]
+    pub fn load(dir_path: &Path) -> Result<Config> {
+        let config: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::file(dir_path.join("rplace.toml")))
+            .merge(Env::prefixed("RPLACE_").split("__"))
+            .extract()
+            .context("Failed to load configuration")?;
+        // Also rejects values too large for a Duration, which cooldown() would panic on
+        if Duration::try_from_secs_f64(config.cooldown_seconds).is_err() {
+            bail!("Invalid configuration: cooldown_seconds must be a non-negative number");
+        }
+        // Sessions have to end at a time that fits into the clock and into a cookie
+        if config
+            .session_lifetime_hours
+            .checked_mul(HOUR)
+            .and_then(|secs| session::expiry(Duration::from_secs(secs)))
+            .is_none()
+        {
+            bail!("Invalid configuration: session_lifetime_hours is too large");
+        }
+        // Tokens have to fit into the one-byte length of the binary websocket protocol
+        if !(16..=64).contains(&config.token_bytes) {
+            bail!("Invalid configuration: token_bytes must be from 16 to 64");
+        }
+        for region in &config.regions {
+            if region.width == 0 || region.height == 0 {
+                bail!("Invalid configuration: regions must not be empty");
+            }
+            if region.reveal_at.is_some() && region.state != RegionState::Hidden {
+                bail!("Invalid configuration: reveal_at can only be set for hidden regions");
+            }
+            if region.reveal_at.is_some() && region.reveal_time().is_none() {
+                bail!("Invalid configuration: reveal_at is too far in the future");
+            }
+        }
+        if let Some(ref snapshots) = config.snapshots {
+            if snapshots.interval_minutes == 0 {
+                bail!("Invalid configuration: snapshots.interval_minutes must be positive");
+            }
+            // So that the durations below can't overflow
+            if snapshots.interval_minutes.checked_mul(60).is_none() {
+                bail!("Invalid configuration: snapshots.interval_minutes is too large");
+            }
+            if snapshots.keep_hourly_hours.checked_mul(HOUR).is_none() {
+                bail!("Invalid configuration: snapshots.keep_hourly_hours is too large");
+            }
+            if snapshots
+                .keep_daily_days
+                .is_some_and(|days| days.checked_mul(DAY).is_none())
+            {
+                bail!("Invalid configuration: snapshots.keep_daily_days is too large");
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs_f64(self.cooldown_seconds)
+    }
+
+    pub fn session_lifetime(&self) -> Duration {
+        Duration::from_secs(self.session_lifetime_hours * HOUR)
+    }
+}
+
+impl SnapshotConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_minutes * 60)
+    }
+
+    pub fn keep_hourly(&self) -> Duration {
+        Duration::from_secs(self.keep_hourly_hours * HOUR)
+    }
+
+    pub fn keep_daily(&self) -> Option<Duration> {
+        self.keep_daily_days
+            .map(|days| Duration::from_secs(days * DAY))
+    }
+}
@@ -1,10 +1,20 @@
-use anyhow::{bail, Result};
+use crate::auth::{AuthProvider, Credentials};
+use anyhow::{bail, Context, Result};
 
-const AUTH_LINKS: &'static [u32] = &[0, 31027, 32030, 33030, 34030, 35025];
+pub struct EjudgeProvider {
+    pub url: String,
+    // The group selected on the form is a 1-based index into this list
+    pub contests: Vec<u32>,
+}
 
-async fn check_contest(login: String, password: String, contest_id: u32) -> Result<bool> {
+async fn check_contest(
+    url: &str,
+    login: String,
+    password: String,
+    contest_id: u32,
+) -> Result<bool> {
     let res = reqwest::Client::new()
-        .post("https://ejudge.algocode.ru/cgi-bin/new-client")
+        .post(url)
         .form(&[
             ("contest_id", contest_id.to_string()),
             ("role", "0".to_string()),
@@ -19,22 +29,56 @@ async fn check_contest(login: String, password: String, contest_id: u32) -> Resu
     Ok(!res.text().await?.contains("SID=\"0000000000000000\""))
 }
 
-pub async fn check_account(login: &str, password: &str, group: usize) -> Result<bool> {
-    if group < 1 || group >= AUTH_LINKS.len() {
+async fn check_account(
+    url: &str,
+    contests: &[u32],
+    login: &str,
+    password: &str,
+    group: usize,
+) -> Result<bool> {
+    if group < 1 || group > contests.len() {
         bail!("Invalid group");
     }
+    let group_contest_id = contests[group - 1];
 
-    if check_contest(login.to_string(), password.to_string(), AUTH_LINKS[group]).await? {
+    if check_contest(
+        url,
+        login.to_string(),
+        password.to_string(),
+        group_contest_id,
+    )
+    .await?
+    {
         return Ok(true);
     }
 
-    for contest_id in &AUTH_LINKS[1..] {
-        if *contest_id != AUTH_LINKS[group] {
-            if check_contest(login.to_string(), password.to_string(), *contest_id).await? {
-                return Ok(true);
-            }
+    for contest_id in contests {
+        if *contest_id != group_contest_id
+            && check_contest(url, login.to_string(), password.to_string(), *contest_id).await?
+        {
+            return Ok(true);
         }
     }
 
     Ok(false)
 }
+
+#[rocket::async_trait]
+impl AuthProvider for EjudgeProvider {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<String>> {
+        let group = credentials.group.context("Invalid group")?;
+        if check_account(
+            &self.url,
+            &self.contests,
+            credentials.login,
+            credentials.password,
+            group,
+        )
+        .await?
+        {
+            Ok(Some(format!("ejudge/{}", credentials.login)))
+        } else {
+            Ok(None)
+        }
+    }
+}
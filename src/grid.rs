@@ -1,17 +1,60 @@
 use anyhow::{bail, Context, Result};
 use memmap::MmapMut;
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Version 1 files are "Rplc", version, width, height, and RGBA cells. Version 2 files have the
+// header described in Header::encode, followed by the cells in the pixel format. Version 1 files
+// are upgraded when opened with Grid::open
+pub const VERSION: u32 = 2;
+
+// Pixel formats of version 2 files
+const FORMAT_RGBA8: u32 = 0;
+const FORMAT_PALETTE: u32 = 1;
+
+// Palette indices are stored in a single byte
+pub const MAX_PALETTE_SIZE: usize = 256;
+
+const TRANSPARENT: CellData = CellData {
+    r: 0,
+    g: 0,
+    b: 0,
+    a: 0,
+};
+
+// Everything a grid data file stores besides the cells
+#[derive(Clone)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    // Palette boards store a one-byte index into the palette per cell, other boards store RGBA
+    pub palette: Option<Vec<CellData>>,
+    // The color of cells that were never painted
+    pub background: CellData,
+    // None for files upgraded from versions that didn't record it
+    pub created_at: Option<SystemTime>,
+    // Number of cells placed by users. Admin actions, like wipes and rollbacks, are not counted
+    pub placements: u64,
+}
+
+#[cfg(test)]
+thread_local! {
+    // Makes sync() fail, to test what happens to placements that don't reach the disk
+    pub static FAIL_SYNC: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
 
 pub struct Grid {
-    _width: u32,
-    _height: u32,
+    header: Header,
+    // Version of the file the grid was loaded from, or 0 for anonymous grids
+    version: u32,
     cells_offset: usize,
     mmapped_data: MmapMut,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct CellData {
     pub r: u8,
     pub g: u8,
@@ -19,37 +62,281 @@ pub struct CellData {
     pub a: u8,
 }
 
+// The color a placement asks for. RGBA boards accept any color, palette boards only indices
+#[derive(Copy, Clone)]
+pub enum Color {
+    Rgba(CellData),
+    Index(u8),
+}
+
+impl CellData {
+    // Accepts #rrggbb or #rrggbbaa, with or without the hash
+    pub fn parse(mut color: &str) -> Result<CellData> {
+        if color.starts_with('#') {
+            color = &color[1..];
+        }
+        if (color.len() != 6 && color.len() != 8) || !color.is_ascii() {
+            bail!("Invalid color format: must be #rrggbb or #rrggbbaa");
+        }
+        let component = |i: usize| u8::from_str_radix(&color[i..i + 2], 16);
+        Ok(CellData {
+            r: component(0)?,
+            g: component(2)?,
+            b: component(4)?,
+            a: if color.len() == 8 { component(6)? } else { 255 },
+        })
+    }
+
+    pub fn to_hex(self) -> String {
+        format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> CellData {
+        CellData {
+            r: bytes[0],
+            g: bytes[1],
+            b: bytes[2],
+            a: bytes[3],
+        }
+    }
+}
+
+// Parses a comma-separated list of colors, e.g. "#ffffff,#000000"
+pub fn parse_palette(colors: &str) -> Result<Vec<CellData>> {
+    let palette = colors
+        .split(',')
+        .map(|color| CellData::parse(color.trim()))
+        .collect::<Result<Vec<_>>>()?;
+    check_palette(&palette)?;
+    Ok(palette)
+}
+
+fn check_palette(palette: &[CellData]) -> Result<()> {
+    if palette.is_empty() || palette.len() > MAX_PALETTE_SIZE {
+        bail!(
+            "The palette must have from 1 to {} colors",
+            MAX_PALETTE_SIZE
+        );
+    }
+    for (i, color) in palette.iter().enumerate() {
+        if palette[..i].contains(color) {
+            bail!("Color {} is in the palette twice", color.to_hex());
+        }
+    }
+    Ok(())
+}
+
+impl Header {
+    // The background defaults to transparent, or to the first color on palette boards
+    pub fn new(
+        width: u32,
+        height: u32,
+        palette: Option<Vec<CellData>>,
+        background: Option<CellData>,
+    ) -> Result<Header> {
+        if let Some(ref palette) = palette {
+            check_palette(palette)?;
+        }
+        let background = match (background, &palette) {
+            (Some(background), _) => background,
+            (None, Some(palette)) => palette[0],
+            (None, None) => TRANSPARENT,
+        };
+        if let Some(ref palette) = palette {
+            palette_index(palette, background).context("Invalid background color")?;
+        }
+        Ok(Header {
+            width,
+            height,
+            palette,
+            background,
+            created_at: Some(SystemTime::now()),
+            placements: 0,
+        })
+    }
+
+    fn cell_size(&self) -> usize {
+        if self.palette.is_some() {
+            1
+        } else {
+            4
+        }
+    }
+
+    fn encode_cell(&self, cell: CellData) -> Result<Vec<u8>> {
+        match self.palette {
+            Some(ref palette) => Ok(vec![palette_index(palette, cell)?]),
+            None => Ok(vec![cell.r, cell.g, cell.b, cell.a]),
+        }
+    }
+
+    // "Rplc", u32 version, u32 width, u32 height, u32 pixel format, u64 creation time in ms (0 if
+    // unknown), u64 placement counter, RGBA background, u32 palette size (0 for RGBA boards), RGBA
+    // palette, and the first 4 bytes of the SHA-256 of everything before them
+    fn encode(&self) -> Vec<u8> {
+        let palette = self.palette.as_deref().unwrap_or(&[]);
+        let created_at = self.created_at.map_or(0, |created_at| {
+            created_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64)
+        });
+        let format = if self.palette.is_some() {
+            FORMAT_PALETTE
+        } else {
+            FORMAT_RGBA8
+        };
+
+        let mut header = Vec::with_capacity(48 + 4 * palette.len());
+        header.extend_from_slice(b"Rplc");
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&self.width.to_le_bytes());
+        header.extend_from_slice(&self.height.to_le_bytes());
+        header.extend_from_slice(&format.to_le_bytes());
+        header.extend_from_slice(&created_at.to_le_bytes());
+        header.extend_from_slice(&self.placements.to_le_bytes());
+        let bg = self.background;
+        header.extend_from_slice(&[bg.r, bg.g, bg.b, bg.a]);
+        header.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        for color in palette {
+            header.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+        let checksum = Sha256::digest(&header);
+        header.extend_from_slice(&checksum[..4]);
+        header
+    }
+
+    // Returns the header and its length
+    fn decode(data: &[u8]) -> Result<(Header, usize)> {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        if data.len() < 44 {
+            bail!("Grid data file is too small to contain a header");
+        }
+        let palette_size = u32_at(40) as usize;
+        if palette_size > MAX_PALETTE_SIZE {
+            bail!(
+                "Grid data file has an invalid palette size {}",
+                palette_size
+            );
+        }
+        let checksum_offset = 44 + 4 * palette_size;
+        if data.len() < checksum_offset + 4 {
+            bail!("Grid data file is too small to contain a header");
+        }
+        let checksum = Sha256::digest(&data[..checksum_offset]);
+        if data[checksum_offset..checksum_offset + 4] != checksum[..4] {
+            bail!("Grid data file header is corrupted: checksum mismatch");
+        }
+
+        let palette = match u32_at(16) {
+            FORMAT_RGBA8 if palette_size == 0 => None,
+            FORMAT_PALETTE if palette_size > 0 => Some(
+                data[44..checksum_offset]
+                    .chunks_exact(4)
+                    .map(CellData::from_bytes)
+                    .collect(),
+            ),
+            format => bail!(
+                "Grid data file has pixel format {} with a palette of {} colors",
+                format,
+                palette_size
+            ),
+        };
+        let created_at = match u64_at(20) {
+            0 => None,
+            ms => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(ms)),
+        };
+
+        let header = Header {
+            width: u32_at(8),
+            height: u32_at(12),
+            palette,
+            background: CellData::from_bytes(&data[36..40]),
+            created_at,
+            placements: u64_at(28),
+        };
+        if let Some(ref palette) = header.palette {
+            check_palette(palette).context("Grid data file has an invalid palette")?;
+            palette_index(palette, header.background)
+                .context("Grid data file has an invalid background color")?;
+        }
+        Ok((header, checksum_offset + 4))
+    }
+}
+
+fn open_file(path: &Path) -> Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("Failed to open grid data file")
+}
+
 impl Grid {
-    pub fn create_file(path: &Path, width: u32, height: u32) -> Result<()> {
-        let mut file = File::create(path).context("Failed to create grid data file")?;
-        file.write(b"Rplc")?; // magic
-        file.write(&1u32.to_le_bytes())?; // version
-        file.write(&width.to_le_bytes())?; // width
-        file.write(&height.to_le_bytes())?; // height
-        file.set_len(16 + 4u64 * (width as u64) * (height as u64))?; // zero-fill data
+    // All cells are set to the background color
+    pub fn create_file(path: &Path, header: &Header) -> Result<()> {
+        let file = File::create(path).context("Failed to create grid data file")?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&header.encode())?;
+        let cell = header.encode_cell(header.background)?;
+        for _ in 0..(header.width as u64) * (header.height as u64) {
+            writer.write_all(&cell)?;
+        }
+        writer.flush()?;
         Ok(())
     }
 
     pub fn create_file_with_data(
         path: &Path,
-        width: u32,
-        height: u32,
+        header: &Header,
         data: &[Vec<CellData>],
     ) -> Result<()> {
-        let mut file = File::create(path).context("Failed to create grid data file")?;
-        file.write(b"Rplc")?; // magic
-        file.write(&1u32.to_le_bytes())?; // version
-        file.write(&width.to_le_bytes())?; // width
-        file.write(&height.to_le_bytes())?; // height
-        for y in 0..height {
-            for x in 0..width {
-                let cell = data[y as usize][x as usize];
-                file.write(&[cell.r, cell.g, cell.b, cell.a])?;
+        let file = File::create(path).context("Failed to create grid data file")?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&header.encode())?;
+        for y in 0..header.height {
+            for x in 0..header.width {
+                writer.write_all(&header.encode_cell(data[y as usize][x as usize])?)?;
             }
         }
+        // The file is renamed over the live grid afterwards, so it must be complete on disk first
+        writer.flush()?;
+        writer
+            .get_ref()
+            .sync_all()
+            .context("Failed to sync grid data file")?;
         Ok(())
     }
 
+    // Loads the grid data file at `path` for reading and writing. Files of older versions are
+    // upgraded to the current one first
+    pub fn open(path: &Path) -> Result<Grid> {
+        let grid = Grid::from_file(&open_file(path)?)?;
+        if grid.version == VERSION {
+            return Ok(grid);
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        Grid::create_file_with_data(
+            &tmp_path,
+            &grid.header,
+            &grid
+                .resized_data(grid.width(), grid.height())
+                .context("Failed to upgrade grid data file")?,
+        )?;
+        std::fs::rename(&tmp_path, path).context("Failed to replace grid data file")?;
+        println!(
+            "Upgraded grid data file from version {} to {}",
+            grid.version, VERSION
+        );
+        drop(grid);
+
+        Grid::from_file(&open_file(path)?)
+    }
+
     pub fn from_file(file: &File) -> Result<Grid> {
         let mmapped_data =
             unsafe { MmapMut::map_mut(file) }.context("Failed to mmap grid data file")?;
@@ -63,37 +350,147 @@ impl Grid {
         }
 
         let version = u32::from_le_bytes(mmapped_data[4..8].try_into().unwrap());
-        match version {
+        let (header, cells_offset) = match version {
             1 => {
+                if mmapped_data.len() < 16 {
+                    bail!("Grid data file is too small to contain a header");
+                }
                 let width = u32::from_le_bytes(mmapped_data[8..12].try_into().unwrap());
                 let height = u32::from_le_bytes(mmapped_data[12..16].try_into().unwrap());
-                if mmapped_data.len() != 16 + 4usize * (width as usize) * (height as usize) {
-                    bail!("Grid data file is of invalid size");
-                }
-                Ok(Grid {
-                    _width: width,
-                    _height: height,
-                    cells_offset: 16,
-                    mmapped_data,
-                })
+                let header = Header {
+                    width,
+                    height,
+                    palette: None,
+                    background: TRANSPARENT,
+                    created_at: None,
+                    placements: 0,
+                };
+                (header, 16)
             }
+            2 => Header::decode(&mmapped_data)?,
             _ => bail!("Grid data file is of unknown version {}", version),
+        };
+
+        let cells_len = header.cell_size() * (header.width as usize) * (header.height as usize);
+        if mmapped_data.len() != cells_offset + cells_len {
+            bail!("Grid data file is of invalid size");
         }
+
+        Ok(Grid {
+            header,
+            version,
+            cells_offset,
+            mmapped_data,
+        })
+    }
+
+    // An RGBA grid in memory with all cells set to the background color
+    pub fn new_anonymous(width: u32, height: u32, background: CellData) -> Result<Grid> {
+        let mut mmapped_data = MmapMut::map_anon(4usize * (width as usize) * (height as usize))
+            .context("Failed to allocate grid data")?;
+        for cell in mmapped_data.chunks_exact_mut(4) {
+            cell.copy_from_slice(&[background.r, background.g, background.b, background.a]);
+        }
+        Ok(Grid {
+            header: Header {
+                width,
+                height,
+                palette: None,
+                background,
+                created_at: None,
+                placements: 0,
+            },
+            version: 0,
+            cells_offset: 0,
+            mmapped_data,
+        })
     }
 
     pub fn width(&self) -> u32 {
-        self._width
+        self.header.width
     }
 
     pub fn height(&self) -> u32 {
-        self._height
+        self.header.height
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn palette(&self) -> Option<&[CellData]> {
+        self.header.palette.as_deref()
+    }
+
+    // Size of a cell in get_data_serialized
+    pub fn cell_size(&self) -> usize {
+        self.header.cell_size()
+    }
+
+    // The color of cells that were never painted
+    pub fn empty_cell(&self) -> CellData {
+        self.header.background
+    }
+
+    // The background as stored in get_data_serialized
+    pub fn empty_cell_serialized(&self) -> Vec<u8> {
+        self.header.encode_cell(self.header.background).unwrap()
+    }
+
+    // Contents of the grid data file, header included
+    pub fn raw_data(&self) -> &[u8] {
+        &self.mmapped_data
+    }
+
+    // Bumps the placement counter in the header. The change reaches the disk with the next flush
+    pub fn count_placement(&mut self) {
+        self.header.placements += 1;
+        self.write_header();
+    }
+
+    // Takes back count_placement, e.g. for a placement that failed to reach the disk
+    pub fn uncount_placement(&mut self) {
+        self.header.placements -= 1;
+        self.write_header();
+    }
+
+    fn write_header(&mut self) {
+        if self.version == VERSION {
+            let header = self.header.encode();
+            self.mmapped_data[..header.len()].copy_from_slice(&header);
+        }
+    }
+
+    // Returns the number of cells that refer to colors outside of the palette
+    pub fn count_invalid_cells(&self) -> usize {
+        match self.header.palette {
+            Some(ref palette) => self.mmapped_data[self.cells_offset..]
+                .iter()
+                .filter(|&&index| index as usize >= palette.len())
+                .count(),
+            None => 0,
+        }
+    }
+
+    fn cell_offset(&self, x: usize, y: usize) -> Result<usize> {
+        if !(x < (self.header.width as usize) && y < (self.header.height as usize)) {
+            bail!("Cell coordinates are out of bounds: X must be from 0 to {}, Y must be from 0 to {}, got X = {}, Y = {}", self.header.width - 1, self.header.height - 1, x, y);
+        }
+        Ok(self.cells_offset + (y * (self.header.width as usize) + x) * self.cell_size())
     }
 
     pub fn get_cell(&self, x: usize, y: usize) -> Result<CellData> {
-        if !(x < (self._width as usize) && y < (self._height as usize)) {
-            bail!("Cell coordinates are out of bounds: X must be from 0 to {}, Y must be from 0 to {}, got X = {}, Y = {}", self._width - 1, self._height - 1, x, y);
+        let offset = self.cell_offset(x, y)?;
+        if let Some(ref palette) = self.header.palette {
+            let index = self.mmapped_data[offset] as usize;
+            return palette.get(index).copied().with_context(|| {
+                format!("Cell ({}, {}) has invalid palette index {}", x, y, index)
+            });
         }
-        let offset = self.cells_offset + (y * (self._width as usize) + x) * 4;
         let r = self.mmapped_data[offset];
         let g = self.mmapped_data[offset + 1];
         let b = self.mmapped_data[offset + 2];
@@ -102,23 +499,139 @@ impl Grid {
     }
 
     pub fn set_cell(&mut self, x: usize, y: usize, value: CellData) -> Result<()> {
-        if !(x < (self._width as usize) && y < (self._height as usize)) {
-            bail!("Cell coordinates are out of bounds: X must be from 0 to {}, Y must be from 0 to {}, got X = {}, Y = {}", self._width - 1, self._height - 1, x, y);
+        self.write_cell(x, y, value)?;
+        self.flush()
+    }
+
+    // Like set_cell, but doesn't flush the data to disk. Use this for bulk updates and call
+    // flush afterwards
+    pub fn write_cell(&mut self, x: usize, y: usize, value: CellData) -> Result<()> {
+        let offset = self.cell_offset(x, y)?;
+        if let Some(ref palette) = self.header.palette {
+            self.mmapped_data[offset] = palette_index(palette, value)?;
+            return Ok(());
         }
-        let offset = self.cells_offset + (y * (self._width as usize) + x) * 4;
         self.mmapped_data[offset] = value.r;
         self.mmapped_data[offset + 1] = value.g;
         self.mmapped_data[offset + 2] = value.b;
         self.mmapped_data[offset + 3] = value.a;
+        Ok(())
+    }
+
+    // Fails if the color can't be stored in the grid, i.e. isn't in the palette
+    pub fn check_color(&self, value: CellData) -> Result<()> {
+        if let Some(ref palette) = self.header.palette {
+            palette_index(palette, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn resolve_color(&self, color: Color) -> Result<CellData> {
+        match (color, &self.header.palette) {
+            (Color::Rgba(cell), None) => Ok(cell),
+            (Color::Index(index), Some(palette)) => {
+                palette.get(index as usize).copied().with_context(|| {
+                    format!(
+                        "Palette index {} is out of range: the palette has {} colors",
+                        index,
+                        palette.len()
+                    )
+                })
+            }
+            (Color::Rgba(_), Some(_)) => {
+                bail!("This board uses a palette: colors must be picked by their palette index")
+            }
+            (Color::Index(_), None) => {
+                bail!("This board doesn't use a palette: colors must be given as RGBA")
+            }
+        }
+    }
+
+    // The palette color closest to the given one, or the color itself on RGBA boards
+    pub fn nearest_color(&self, value: CellData) -> CellData {
+        let palette = match self.header.palette {
+            Some(ref palette) => palette,
+            None => return value,
+        };
+        let distance = |color: &CellData| {
+            [
+                (color.r, value.r),
+                (color.g, value.g),
+                (color.b, value.b),
+                (color.a, value.a),
+            ]
+            .iter()
+            .map(|&(a, b)| (a as i32 - b as i32).pow(2))
+            .sum::<i32>()
+        };
+        *palette.iter().min_by_key(|color| distance(color)).unwrap()
+    }
+
+    pub fn flush(&self) -> Result<()> {
         self.mmapped_data
             .flush_async()
             .context("Failed to flush grid data to disk")
     }
 
+    // Like flush, but waits until the data is on disk
+    pub fn sync(&self) -> Result<()> {
+        #[cfg(test)]
+        if FAIL_SYNC.get() {
+            bail!("Failed to flush grid data to disk: injected failure");
+        }
+        self.mmapped_data
+            .flush()
+            .context("Failed to flush grid data to disk")
+    }
+
+    // Cells that don't fit into the new size are cut off, new cells are empty. Fails on cells with
+    // an invalid palette index, which fsck reports
+    pub fn resized_data(&self, width: u32, height: u32) -> Result<Vec<Vec<CellData>>> {
+        let mut data = Vec::with_capacity(height as usize);
+        for y in 0..height {
+            let mut row = Vec::with_capacity(width as usize);
+            for x in 0..width {
+                if x < self.header.width && y < self.header.height {
+                    row.push(self.get_cell(x as usize, y as usize)?);
+                } else {
+                    row.push(self.empty_cell());
+                }
+            }
+            data.push(row);
+        }
+        Ok(data)
+    }
+
+    // The cells as stored: RGBA, or palette indices on palette boards
     pub fn get_data_serialized(&self) -> Vec<u8> {
         Vec::from(
             &self.mmapped_data[self.cells_offset
-                ..self.cells_offset + (self._width as usize) * (self._height as usize) * 4],
+                ..self.cells_offset
+                    + (self.header.width as usize)
+                        * (self.header.height as usize)
+                        * self.cell_size()],
         )
     }
+
+    // The cells as RGBA, regardless of how they are stored
+    pub fn get_data_rgba(&self) -> Vec<u8> {
+        let data = self.get_data_serialized();
+        match self.header.palette {
+            Some(ref palette) => data
+                .iter()
+                .flat_map(|&index| {
+                    let color = palette.get(index as usize).copied().unwrap_or(TRANSPARENT);
+                    [color.r, color.g, color.b, color.a]
+                })
+                .collect(),
+            None => data,
+        }
+    }
+}
+
+fn palette_index(palette: &[CellData], value: CellData) -> Result<u8> {
+    match palette.iter().position(|&color| color == value) {
+        Some(index) => Ok(index as u8),
+        None => bail!("Color {} is not in the palette", value.to_hex()),
+    }
 }
@@ -0,0 +1,360 @@
+use crate::grid::CellData;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const HEADER_SIZE: u64 = 8;
+
+pub struct History {
+    file: File,
+    // Whether this process holds the lock on the file, see lock()
+    locked: bool,
+}
+
+pub struct Placement {
+    pub timestamp: SystemTime,
+    pub uid: String,
+    pub x: u32,
+    pub y: u32,
+    pub color: CellData,
+    pub previous_color: CellData,
+}
+
+pub struct HistoryReader {
+    reader: BufReader<File>,
+    // Whether the last record was cut short by the end of the file
+    truncated: bool,
+}
+
+impl History {
+    // Opens the history for appending. Fails if the file is damaged, including a partial record
+    // at the end: a running server would have finished it, so it is left by a crash, and only
+    // the server cuts it off on start, see recover()
+    pub fn open(path: &Path) -> Result<History> {
+        let (file, valid_len) = History::open_file(path)?;
+        if valid_len < file.metadata()?.len() {
+            bail!(
+                "History file ends with a partial record at offset {}. Start the server to drop it",
+                valid_len
+            );
+        }
+        file.unlock().context("Failed to unlock history file")?;
+        Ok(History {
+            file,
+            locked: false,
+        })
+    }
+
+    // Same, but cuts off a partial record at the end, which a crash in the middle of an append
+    // leaves behind, so that new records are not glued to it. Only for the server on start
+    pub fn recover(path: &Path) -> Result<History> {
+        let (file, valid_len) = History::open_file(path)?;
+        if valid_len < file.metadata()?.len() {
+            eprintln!(
+                "History file ends with a partial record at offset {}, dropping it",
+                valid_len
+            );
+            file.set_len(valid_len)
+                .context("Failed to truncate history file")?;
+        }
+        file.unlock().context("Failed to unlock history file")?;
+        Ok(History {
+            file,
+            locked: false,
+        })
+    }
+
+    // Returns the locked file and the length of its complete records. The lock keeps other
+    // processes from appending while the file is checked
+    fn open_file(path: &Path) -> Result<(File, u64)> {
+        let mut file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .context("Failed to open history file")?;
+        file.lock().context("Failed to lock history file")?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(b"Rplh")?; // magic
+            file.write_all(&1u32.to_le_bytes())?; // version
+            file.sync_all()?;
+        }
+
+        let mut reader = HistoryReader::new(file.try_clone()?)?;
+        let mut valid_len = HEADER_SIZE;
+        loop {
+            match reader.read_placement() {
+                Ok(Some(placement)) => valid_len += placement.encoded_len(),
+                Ok(None) => break,
+                // The file ended in the middle of the record
+                Err(_) if reader.truncated => break,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("History file is damaged at offset {}", valid_len)
+                    })
+                }
+            }
+        }
+        Ok((file, valid_len))
+    }
+
+    // Appends a record under the lock, unless the caller holds it already, and drops it again if
+    // it was only written partially
+    pub fn append(&mut self, placement: &Placement) -> Result<()> {
+        let buf = placement.try_to_buf()?;
+        let locked = self.locked;
+        let len = if locked { self.len()? } else { self.lock()? };
+        let mut result = self
+            .file
+            .write_all(&buf)
+            .context("Failed to append to history file");
+        if result.is_err() {
+            result = result.and(self.truncate(len));
+        }
+        if !locked {
+            result = result.and(self.unlock());
+        }
+        result
+    }
+
+    // Keeps other processes from appending until unlock(), so that the records appended in the
+    // meantime can be dropped with truncate(). Returns the current size of the file
+    pub fn lock(&mut self) -> Result<u64> {
+        self.file.lock().context("Failed to lock history file")?;
+        self.locked = true;
+        self.len()
+    }
+
+    pub fn unlock(&mut self) -> Result<()> {
+        self.locked = false;
+        self.file.unlock().context("Failed to unlock history file")
+    }
+
+    pub fn len(&self) -> Result<u64> {
+        Ok(self
+            .file
+            .metadata()
+            .context("Failed to stat history file")?
+            .len())
+    }
+
+    // Drops the records after `len`, e.g. of a placement that did not make it into the grid. Only
+    // while locked, so that the records of other processes are kept
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        if !self.locked {
+            bail!("History file must be locked to be truncated");
+        }
+        self.file
+            .set_len(len)
+            .context("Failed to truncate history file")
+    }
+
+    pub fn read(path: &Path) -> Result<HistoryReader> {
+        HistoryReader::new(File::open(path).context("Failed to open history file")?)
+    }
+}
+
+impl HistoryReader {
+    fn new(file: File) -> Result<HistoryReader> {
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        reader
+            .read_exact(&mut header)
+            .context("History file is too small to contain a header")?;
+        if &header[..4] != b"Rplh" {
+            bail!("History file does not contain a valid header");
+        }
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != 1 {
+            bail!("History file is of unknown version {}", version);
+        }
+
+        Ok(HistoryReader {
+            reader,
+            truncated: false,
+        })
+    }
+
+    fn read_placement(&mut self) -> Result<Option<Placement>> {
+        let mut fixed = [0u8; 26];
+        match self.reader.read_exact(&mut fixed[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        self.read_rest(&mut fixed[1..])?;
+
+        let timestamp = u64::from_le_bytes(fixed[..8].try_into().unwrap());
+        let x = u32::from_le_bytes(fixed[8..12].try_into().unwrap());
+        let y = u32::from_le_bytes(fixed[12..16].try_into().unwrap());
+        let color = CellData {
+            r: fixed[16],
+            g: fixed[17],
+            b: fixed[18],
+            a: fixed[19],
+        };
+        let previous_color = CellData {
+            r: fixed[20],
+            g: fixed[21],
+            b: fixed[22],
+            a: fixed[23],
+        };
+        let uid_len = u16::from_le_bytes(fixed[24..26].try_into().unwrap());
+
+        let mut uid = vec![0u8; uid_len as usize];
+        self.read_rest(&mut uid)?;
+        let uid = String::from_utf8(uid).context("Failed to parse UID")?;
+
+        Ok(Some(Placement {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp),
+            uid,
+            x,
+            y,
+            color,
+            previous_color,
+        }))
+    }
+
+    // Reads the rest of a record that has already started
+    fn read_rest(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                bail!("History record is truncated")
+            }
+            result => result.context("Failed to read history file"),
+        }
+    }
+}
+
+impl Iterator for HistoryReader {
+    type Item = Result<Placement>;
+
+    fn next(&mut self) -> Option<Result<Placement>> {
+        self.read_placement().transpose()
+    }
+}
+
+impl Placement {
+    fn encoded_len(&self) -> u64 {
+        26 + self.uid.len() as u64
+    }
+
+    fn try_to_buf(&self) -> Result<Vec<u8>> {
+        let timestamp = self
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64;
+        let uid = self.uid.as_bytes();
+        if uid.len() > u16::MAX as usize {
+            bail!("UID is too long to be stored in history");
+        }
+
+        let mut buf = Vec::with_capacity(26 + uid.len());
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        buf.extend_from_slice(&self.x.to_le_bytes());
+        buf.extend_from_slice(&self.y.to_le_bytes());
+        buf.extend_from_slice(&[self.color.r, self.color.g, self.color.b, self.color.a]);
+        buf.extend_from_slice(&[
+            self.previous_color.r,
+            self.previous_color.g,
+            self.previous_color.b,
+            self.previous_color.a,
+        ]);
+        buf.extend_from_slice(&(uid.len() as u16).to_le_bytes());
+        buf.extend_from_slice(uid);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(uid: &str) -> Placement {
+        let color = CellData {
+            r: 1,
+            g: 2,
+            b: 3,
+            a: 255,
+        };
+        Placement {
+            timestamp: SystemTime::now(),
+            uid: uid.to_string(),
+            x: 1,
+            y: 2,
+            color,
+            previous_color: color,
+        }
+    }
+
+    fn history_with_records(path: &Path) -> u64 {
+        let mut history = History::open(path).unwrap();
+        history.append(&placement("alice")).unwrap();
+        history.append(&placement("bob")).unwrap();
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn only_recover_drops_partial_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let len = history_with_records(&path);
+        let mut file = File::options().append(true).open(&path).unwrap();
+        file.write_all(&placement("carol").try_to_buf().unwrap()[..10])
+            .unwrap();
+
+        assert!(History::open(&path).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len + 10);
+        History::recover(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(History::read(&path).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn truncate_keeps_records_of_other_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        history_with_records(&path);
+        let mut history = History::open(&path).unwrap();
+
+        let len = history.lock().unwrap();
+        // Blocks until the lock is released
+        let other = std::thread::spawn({
+            let path = path.clone();
+            move || History::open(&path).unwrap().append(&placement("carol"))
+        });
+        history.append(&placement("dave")).unwrap();
+        history.truncate(len).unwrap();
+        history.unlock().unwrap();
+        other.join().unwrap().unwrap();
+
+        let uids: Vec<String> = History::read(&path)
+            .unwrap()
+            .map(|placement| placement.unwrap().uid)
+            .collect();
+        assert_eq!(uids, ["alice", "bob", "carol"]);
+        assert!(history.truncate(len).is_err());
+    }
+
+    #[test]
+    fn damaged_record_is_not_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let len = history_with_records(&path);
+        // Invalid UTF-8 in the UID of the first record
+        let mut data = std::fs::read(&path).unwrap();
+        data[HEADER_SIZE as usize + 26] = 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        assert!(History::open(&path).is_err());
+        assert!(History::recover(&path).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+}
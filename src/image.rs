@@ -0,0 +1,177 @@
+use crate::grid::CellData;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+pub fn encode_png<W: Write>(writer: W, width: u32, height: u32, data: &[u8]) -> Result<()> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .context("Failed to write PNG header")?;
+    writer
+        .write_image_data(data)
+        .context("Failed to write PNG data")?;
+    writer.finish().context("Failed to finish PNG")?;
+    Ok(())
+}
+
+pub fn write_png(path: &Path, width: u32, height: u32, data: &[u8]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    encode_png(BufWriter::new(file), width, height, data)
+}
+
+// Exported images are limited in size so that a single export can't eat all memory
+pub const MAX_EXPORT_PIXELS: u64 = 1 << 26;
+
+// /board.png can be requested by anyone, so it gets a much lower limit
+pub const MAX_HTTP_EXPORT_PIXELS: u64 = 4096 * 4096;
+
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Crop {
+    // Missing coordinates default to the top left corner, missing sizes to the rest of the grid
+    pub fn resolve(
+        x: Option<u32>,
+        y: Option<u32>,
+        width: Option<u32>,
+        height: Option<u32>,
+        grid_width: u32,
+        grid_height: u32,
+    ) -> Result<Crop> {
+        let x = x.unwrap_or(0);
+        let y = y.unwrap_or(0);
+        if x >= grid_width || y >= grid_height {
+            bail!(
+                "Crop origin is out of bounds: X must be from 0 to {}, Y must be from 0 to {}, got X = {}, Y = {}",
+                grid_width as i64 - 1,
+                grid_height as i64 - 1,
+                x,
+                y
+            );
+        }
+        let width = width.unwrap_or(grid_width - x);
+        let height = height.unwrap_or(grid_height - y);
+        if width == 0 || height == 0 {
+            bail!("Crop size must be positive");
+        }
+        if width > grid_width - x || height > grid_height - y {
+            bail!("Crop rectangle does not fit into the grid");
+        }
+        Ok(Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+// Cuts the crop rectangle out of serialized grid data and scales each cell up to a
+// `scale` x `scale` square, unless the image would have more than `max_pixels` pixels. Returns the
+// size of the image and its RGBA data
+pub fn crop_and_scale(
+    data: &[u8],
+    grid_width: u32,
+    crop: &Crop,
+    scale: u32,
+    max_pixels: u64,
+) -> Result<(u32, u32, Vec<u8>)> {
+    if scale == 0 {
+        bail!("Scale must be positive");
+    }
+    let width = crop.width as u64 * scale as u64;
+    let height = crop.height as u64 * scale as u64;
+    if width * height > max_pixels {
+        bail!(
+            "The exported image would be {} x {}, which is too large",
+            width,
+            height
+        );
+    }
+
+    let mut out = Vec::with_capacity((width * height * 4) as usize);
+    for y in crop.y..crop.y + crop.height {
+        let row_start = out.len();
+        for x in crop.x..crop.x + crop.width {
+            let offset = (y as usize * grid_width as usize + x as usize) * 4;
+            for _ in 0..scale {
+                out.extend_from_slice(&data[offset..offset + 4]);
+            }
+        }
+        let row_end = out.len();
+        for _ in 1..scale {
+            out.extend_from_within(row_start..row_end);
+        }
+    }
+    Ok((width as u32, height as u32, out))
+}
+
+pub fn export_png<W: Write>(
+    writer: W,
+    data: &[u8],
+    grid_width: u32,
+    crop: &Crop,
+    scale: u32,
+    max_pixels: u64,
+) -> Result<()> {
+    let (width, height, pixels) = crop_and_scale(data, grid_width, crop, scale, max_pixels)?;
+    encode_png(writer, width, height, &pixels)
+}
+
+// Reads a PNG file of any color type and converts it to 8-bit RGBA
+pub fn read_png(path: &Path) -> Result<(u32, u32, Vec<u8>)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().context("Failed to read PNG header")?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .context("Failed to read PNG data")?;
+    buf.truncate(info.buffer_size());
+
+    let data = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => bail!("Failed to expand palette of PNG image"),
+    };
+    Ok((info.width, info.height, data))
+}
+
+// Draws `src` over `dst` using the usual "over" compositing operator
+pub fn blend(dst: CellData, src: CellData) -> CellData {
+    let src_a = src.a as u32;
+    let dst_a = dst.a as u32 * (255 - src_a) / 255;
+    let out_a = src_a + dst_a;
+    if out_a == 0 {
+        return CellData {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        };
+    }
+    let mix = |s: u8, d: u8| ((s as u32 * src_a + d as u32 * dst_a + out_a / 2) / out_a) as u8;
+    CellData {
+        r: mix(src.r, dst.r),
+        g: mix(src.g, dst.g),
+        b: mix(src.b, dst.b),
+        a: out_a as u8,
+    }
+}
@@ -1,92 +1,903 @@
-#![feature(duration_constants)]
-
+mod admin;
+mod audit;
+mod auth;
+mod config;
 mod ejudge;
 mod grid;
+mod history;
+mod image;
+mod oidc;
+mod protocol;
+mod regions;
+mod rollback;
+mod session;
+mod snapshot;
+mod timelapse;
 mod tokendb;
+mod websocket;
 
-use anyhow::{bail, Context, Result};
-use futures_util::{stream::SplitSink, SinkExt};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{Sink, SinkExt, Stream};
+use rand::rngs::OsRng;
+use rand::RngCore;
 use rocket::{
     form::Form,
     fs::FileServer,
-    futures::{StreamExt, TryStreamExt},
+    futures::StreamExt,
+    http::{ContentType, CookieJar},
+    response::{status::BadRequest, Redirect},
     routes, FromForm, State,
 };
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify, RwLock};
 use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::HeaderValue;
 use tungstenite::protocol::Message;
 
-fn get_cooldown_period() -> Duration {
-    return 10 * Duration::SECOND;
-}
+// UID that admin wipes are recorded under in the history
+const WIPE_UID: &str = "wipe";
+
+const BROADCAST_TICK: Duration = Duration::from_millis(50);
+
+// How often the reveal times of hidden regions are checked
+const REVEAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often it is checked whether a snapshot is due
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// Clients that send nothing, not even a pong, for this long are disconnected
+const WS_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// Clients that have this many messages queued are too slow to keep up with updates and are
+// disconnected
+const WS_QUEUE_LIMIT: usize = 1024;
 
 struct GlobalState {
+    dir_path: String,
+    config: std::sync::RwLock<Arc<config::Config>>,
+    auth_provider: std::sync::RwLock<Arc<dyn auth::AuthProvider>>,
+    oidc: std::sync::RwLock<Option<Arc<oidc::OidcClient>>>,
     grid: RwLock<grid::Grid>,
+    history: Mutex<history::History>,
     tokendb: tokendb::TokenDB,
-    ws_connections: Arc<
-        RwLock<HashMap<SocketAddr, Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>>>,
-    >,
+    audit: Mutex<audit::AuditLog>,
+    ws_connections: Arc<RwLock<HashMap<u64, WsConnection>>>,
+    next_ws_connection_id: AtomicU64,
+    grid_updates: mpsc::UnboundedSender<(u32, u32, grid::CellData)>,
+    pending_placements: mpsc::UnboundedSender<PendingPlacement>,
+}
+
+// A placement that has been charged for and journaled and waits for run_committer to write it
+struct PendingPlacement {
+    entry: tokendb::JournalEntry,
+    token_use: tokendb::TokenUse,
+    done: oneshot::Sender<Result<()>>,
+}
+
+struct WsConnection {
+    protocol: protocol::Protocol,
+    outgoing: mpsc::Sender<Message>,
+    dropped: Arc<Notify>,
 }
 
 impl GlobalState {
-    async fn broadcast_grid_update(&self, x: usize, y: usize, cell: grid::CellData) {
-        for (_, ws) in self.ws_connections.read().await.iter() {
-            let ws = ws.clone();
-            tokio::spawn(async move {
-                ws.lock()
-                    .await
-                    .send(Message::Text(format!(
-                        "set {} {} {} {} {} {}",
-                        x, y, cell.r, cell.g, cell.b, cell.a
-                    )))
-                    .await
-            });
+    fn config(&self) -> Arc<config::Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    fn auth_provider(&self) -> Arc<dyn auth::AuthProvider> {
+        self.auth_provider.read().unwrap().clone()
+    }
+
+    fn oidc(&self) -> Option<Arc<oidc::OidcClient>> {
+        self.oidc.read().unwrap().clone()
+    }
+
+    // Charges the cooldown of an explicitly given token or, if there is none, of the user logged
+    // in with the session cookie, and journals the placement
+    fn use_token(
+        &self,
+        token: Option<&str>,
+        session: Option<&session::Session>,
+        entry: &tokendb::JournalEntry,
+    ) -> Result<tokendb::TokenUse> {
+        let cooldown = self.config().cooldown();
+        match (token, session) {
+            (Some(token), _) => {
+                self.tokendb
+                    .try_use_token(tokendb::Token::from_string(token), cooldown, entry)
+            }
+            (None, Some(session)) => {
+                self.tokendb
+                    .try_use_token_of_user(session.uid()?, cooldown, entry)
+            }
+            (None, None) => bail!("No token given and not logged in, please visit /get_token"),
         }
     }
+
+    // Must be called after the bounds check, so that the coordinates fit into u32
+    fn check_region(&self, x: usize, y: usize) -> Result<()> {
+        regions::check_writable(
+            &self.config().regions,
+            x as u32,
+            y as u32,
+            SystemTime::now(),
+        )
+    }
+
+    // Grid data as the clients may see it, i.e. without the contents of hidden regions. The cells
+    // are as stored in the grid
+    fn visible_grid_data(&self, grid: &grid::Grid) -> Vec<u8> {
+        let mut data = grid.get_data_serialized();
+        self.mask_hidden(grid, &grid.empty_cell_serialized(), &mut data);
+        data
+    }
+
+    // Same, but always RGBA
+    fn visible_grid_rgba(&self, grid: &grid::Grid) -> Vec<u8> {
+        let mut data = grid.get_data_rgba();
+        let empty = grid.empty_cell();
+        self.mask_hidden(grid, &[empty.r, empty.g, empty.b, empty.a], &mut data);
+        data
+    }
+
+    fn mask_hidden(&self, grid: &grid::Grid, empty_cell: &[u8], data: &mut [u8]) {
+        regions::mask_hidden(
+            &self.config().regions,
+            SystemTime::now(),
+            grid.width(),
+            grid.height(),
+            empty_cell,
+            data,
+        );
+    }
+
+    // Validates the placement, charges the cooldown and applies the placement. Returns the new
+    // color of the cell and whether the user is shadowbanned, in which case the grid is left alone
+    async fn place_cell(
+        &self,
+        token: Option<&str>,
+        session: Option<&session::Session>,
+        x: usize,
+        y: usize,
+        color: grid::Color,
+    ) -> Result<(grid::CellData, bool)> {
+        // Everything that can reject the placement is checked before the cooldown is charged
+        let grid = self.grid.read().await;
+        let cell = grid.resolve_color(color)?;
+        grid.get_cell(x, y)?;
+        drop(grid);
+        self.check_region(x, y)?;
+
+        let entry = tokendb::JournalEntry {
+            timestamp: SystemTime::now(),
+            uid: String::new(),
+            x: x as u32,
+            y: y as u32,
+            color: cell,
+        };
+        let token_use = self.use_token(token, session, &entry)?;
+        if token_use.journal_id.is_none() {
+            // Shadowbanned
+            return Ok((cell, true));
+        }
+
+        let entry = tokendb::JournalEntry {
+            uid: token_use.uid.clone(),
+            ..entry
+        };
+        let (done, result) = oneshot::channel();
+        // The committer runs as long as the server does
+        let _ = self.pending_placements.send(PendingPlacement {
+            entry,
+            token_use,
+            done,
+        });
+        result.await.context("Placement was dropped")??;
+
+        Ok((cell, false))
+    }
+
+    async fn rollback(&self, filter: &rollback::Filter) -> Result<usize> {
+        let mut grid = self.grid.write().await;
+        let mut history = self.history.lock().await;
+        let cells = rollback::apply(
+            &mut grid,
+            &mut history,
+            format!("{}/history", self.dir_path).as_ref(),
+            filter,
+        )?;
+        drop(history);
+        drop(grid);
+
+        for &(x, y, cell) in &cells {
+            self.broadcast_grid_update(x as usize, y as usize, cell);
+        }
+
+        Ok(cells.len())
+    }
+
+    // Paints a rectangle with a single color, e.g. to clean up vandalism. Cells that already have
+    // the color are left alone. Without a color, the cells are emptied
+    async fn wipe(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        color: Option<grid::CellData>,
+    ) -> Result<usize> {
+        let mut grid = self.grid.write().await;
+        let color = color.unwrap_or_else(|| grid.empty_cell());
+        grid.check_color(color)?;
+        let crop = image::Crop::resolve(
+            Some(x),
+            Some(y),
+            Some(width),
+            Some(height),
+            grid.width(),
+            grid.height(),
+        )?;
+
+        let mut history = self.history.lock().await;
+        let mut cells = Vec::new();
+        for y in crop.y..crop.y + crop.height {
+            for x in crop.x..crop.x + crop.width {
+                let previous_color = grid.get_cell(x as usize, y as usize)?;
+                if previous_color == color {
+                    continue;
+                }
+                history.append(&history::Placement {
+                    timestamp: SystemTime::now(),
+                    uid: WIPE_UID.to_string(),
+                    x,
+                    y,
+                    color,
+                    previous_color,
+                })?;
+                grid.write_cell(x as usize, y as usize, color)?;
+                cells.push((x, y));
+            }
+        }
+        grid.flush()?;
+        drop(history);
+        drop(grid);
+
+        for &(x, y) in &cells {
+            self.broadcast_grid_update(x as usize, y as usize, color);
+        }
+
+        Ok(cells.len())
+    }
+
+    // Swaps in a grid of the new size and sends it to all clients. The grid stays locked until the
+    // new grid is queued, so clients can't get updates to the new grid before the grid itself
+    async fn resize(&self, width: u32, height: u32, force: bool) -> Result<()> {
+        let mut grid = self.grid.write().await;
+        let (header, data) = resized_grid(&grid, width, height, force)?;
+        let dir_path = self.dir_path.clone();
+        *grid = tokio::task::spawn_blocking(move || write_resized_grid(&dir_path, &header, &data))
+            .await??;
+
+        let data = self.visible_grid_data(&grid);
+        self.send_to_all(
+            protocol::Protocol::Text.encode_grid(width, height, grid.palette(), data.clone()),
+            protocol::Protocol::Binary.encode_grid(width, height, grid.palette(), data),
+        )
+        .await;
+        Ok(())
+    }
+
+    // Snapshots the grid if the interval has passed since the latest snapshot. Its time is taken
+    // from the snapshot directory, so that restarts don't reset the schedule
+    async fn take_snapshot_if_due(&self, config: &config::SnapshotConfig) -> Result<()> {
+        let dir = snapshot::dir_path(&self.dir_path);
+        let now = SystemTime::now();
+        let snapshots = tokio::task::spawn_blocking({
+            let dir = dir.clone();
+            move || snapshot::list(&dir)
+        })
+        .await??;
+        if let Some(latest) = snapshots.last() {
+            // An interval too large for the clock means that no more snapshots are due
+            if latest
+                .time
+                .checked_add(config.interval())
+                .is_none_or(|due| due > now)
+            {
+                return Ok(());
+            }
+        }
+
+        // Placements need the write lock, so the copy can't contain half of one
+        let data = self.grid.read().await.raw_data().to_vec();
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            snapshot::write(&dir, now, &data)?;
+            snapshot::prune(&dir, &config, now)?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    // Overrides the cooldown until the configuration is reloaded
+    fn set_cooldown(&self, seconds: f64) -> Result<()> {
+        // Same check as in Config::load, so that cooldown() can't panic
+        if Duration::try_from_secs_f64(seconds).is_err() {
+            bail!("Cooldown must be a non-negative number of seconds");
+        }
+        let mut config = self.config.write().unwrap();
+        let mut new_config = (**config).clone();
+        new_config.cooldown_seconds = seconds;
+        *config = Arc::new(new_config);
+        Ok(())
+    }
+
+    // Sends the clients the cells that a configuration change revealed, and the empty cell in place
+    // of those it hid. The updates don't go through the broadcaster, which drops hidden cells
+    async fn push_region_changes(
+        &self,
+        old: &[config::RegionConfig],
+        new: &[config::RegionConfig],
+    ) {
+        let grid = self.grid.read().await;
+        let empty_cell = grid.empty_cell();
+        let updates: Vec<_> =
+            regions::visibility_changes(old, new, SystemTime::now(), grid.width(), grid.height())
+                .into_iter()
+                .filter_map(|(x, y, hidden)| {
+                    let cell = if hidden {
+                        empty_cell
+                    } else {
+                        grid.get_cell(x as usize, y as usize).ok()?
+                    };
+                    Some((x, y, cell))
+                })
+                .collect();
+        drop(grid);
+
+        if !updates.is_empty() {
+            self.send_grid_updates(&updates).await;
+        }
+    }
+
+    // Queues the update for the broadcaster task, which sends it out on the next tick
+    fn broadcast_grid_update(&self, x: usize, y: usize, cell: grid::CellData) {
+        // The broadcaster lives as long as the server, so this can't fail
+        let _ = self.grid_updates.send((x as u32, y as u32, cell));
+    }
+
+    async fn send_grid_updates(&self, updates: &[(u32, u32, grid::CellData)]) {
+        self.send_to_all(
+            protocol::Protocol::Text.encode_updates(updates),
+            protocol::Protocol::Binary.encode_updates(updates),
+        )
+        .await;
+    }
+
+    // Queues the messages of each client's protocol, dropping clients whose queues are full
+    async fn send_to_all(&self, text_messages: Vec<Message>, binary_messages: Vec<Message>) {
+        let mut slow_connections = Vec::new();
+        for (&id, connection) in self.ws_connections.read().await.iter() {
+            let messages = match connection.protocol {
+                protocol::Protocol::Text => &text_messages,
+                protocol::Protocol::Binary => &binary_messages,
+            };
+            for message in messages {
+                if connection.outgoing.try_send(message.clone()).is_err() {
+                    slow_connections.push(id);
+                    break;
+                }
+            }
+        }
+
+        if !slow_connections.is_empty() {
+            let mut connections = self.ws_connections.write().await;
+            for id in slow_connections {
+                if let Some(connection) = connections.remove(&id) {
+                    connection.dropped.notify_one();
+                }
+            }
+        }
+    }
+}
+
+async fn run_snapshotter(state: &'static GlobalState) {
+    let mut interval = tokio::time::interval(SNAPSHOT_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(ref config) = state.config().snapshots {
+            if let Err(e) = state.take_snapshot_if_due(config).await {
+                eprintln!("Failed to take a snapshot: {:?}", e);
+            }
+        }
+    }
+}
+
+// Writes a journaled placement into the grid. The placement is logged before it is applied, so
+// that the history never misses a cell that made it into the grid. Returns the previous color of
+// the cell. On failure, the grid and the history are left as they were. The history must be locked
+// and the grid still has to be synced
+fn apply_placement(
+    grid: &mut grid::Grid,
+    history: &mut history::History,
+    entry: &tokendb::JournalEntry,
+) -> Result<grid::CellData> {
+    let (x, y) = (entry.x as usize, entry.y as usize);
+    let previous_color = grid.get_cell(x, y)?;
+    let history_len = history.len()?;
+    let result = history
+        .append(&history::Placement {
+            timestamp: entry.timestamp,
+            uid: entry.uid.clone(),
+            x: entry.x,
+            y: entry.y,
+            color: entry.color,
+            previous_color,
+        })
+        .and_then(|()| grid.write_cell(x, y, entry.color));
+    if let Err(e) = result {
+        truncate_history(history, history_len);
+        return Err(e);
+    }
+    grid.count_placement();
+    Ok(previous_color)
+}
+
+fn truncate_history(history: &mut history::History, len: u64) {
+    if let Err(e) = history.truncate(len) {
+        eprintln!("Failed to drop failed placements from history: {:?}", e);
+    }
+}
+
+// Applies a batch of placements and syncs the grid once for all of them. If the sync fails, the
+// whole batch is reverted. The history stays locked meanwhile, so that dropping the records of the
+// batch doesn't drop those of other processes. Returns the result of each placement
+fn write_placements(
+    grid: &mut grid::Grid,
+    history: &mut history::History,
+    entries: &[tokendb::JournalEntry],
+) -> Vec<Result<()>> {
+    let history_len = match history.lock() {
+        Ok(len) => len,
+        Err(e) => return entries.iter().map(|_| Err(anyhow!("{:#}", e))).collect(),
+    };
+    let mut results: Vec<Result<grid::CellData>> = entries
+        .iter()
+        .map(|entry| apply_placement(grid, history, entry))
+        .collect();
+
+    if results.iter().any(Result::is_ok) {
+        if let Err(e) = grid.sync() {
+            // Newest first, so that a cell placed on twice gets its original color back
+            for (entry, result) in entries.iter().zip(&mut results).rev() {
+                if let Ok(previous_color) = *result {
+                    grid.uncount_placement();
+                    if let Err(revert_error) =
+                        grid.write_cell(entry.x as usize, entry.y as usize, previous_color)
+                    {
+                        eprintln!(
+                            "Failed to revert the failed placement at ({}, {}): {:?}",
+                            entry.x, entry.y, revert_error
+                        );
+                    }
+                    *result = Err(anyhow!("{:#}", e));
+                }
+            }
+            truncate_history(history, history_len);
+        }
+    }
+    if let Err(e) = history.unlock() {
+        eprintln!("{:?}", e);
+    }
+
+    results
+        .into_iter()
+        .map(|result| result.map(|_| ()))
+        .collect()
+}
+
+// Removes the written placements from the journal and refunds the failed ones, which don't cost
+// the cooldown. If a refund fails, the placement stays in the journal and is retried on the next
+// start
+fn settle_placements(
+    tokendb: &tokendb::TokenDB,
+    token_uses: &[tokendb::TokenUse],
+    results: &[Result<()>],
+) {
+    for (token_use, result) in token_uses.iter().zip(results) {
+        let settled = match result {
+            Ok(()) => match token_use.journal_id {
+                Some(id) => tokendb.remove_from_journal(id),
+                None => Ok(()),
+            },
+            Err(_) => tokendb.refund(token_use),
+        };
+        if let Err(e) = settled {
+            eprintln!(
+                "Failed to settle the placement of user {}: {:?}",
+                token_use.uid, e
+            );
+        }
+    }
+    // Entries that don't make it to disk are skipped on replay, since their cells already have
+    // their colors
+    if let Err(e) = tokendb.flush() {
+        eprintln!("Failed to flush token database: {:?}", e);
+    }
+}
+
+// Applies the placements that were charged for but may not have reached the grid before the server
+// stopped. Placements are logged to the history before they reach the grid, so a placement whose
+// record is in the history has been applied, except that the crash may have happened before its
+// cell was written. Such cells get the color of their latest history record
+fn replay_journal(
+    grid: &mut grid::Grid,
+    history: &mut history::History,
+    history_path: &Path,
+    tokendb: &tokendb::TokenDB,
+) -> Result<()> {
+    let journal = tokendb.journal()?;
+    if journal.is_empty() {
+        return Ok(());
+    }
+    history.lock()?;
+    let replayed = replay_entries(grid, history, history_path, &journal);
+    history.unlock()?;
+    let replayed = replayed?;
+
+    grid.sync()?;
+    for (id, _) in &journal {
+        tokendb.remove_from_journal(*id)?;
+    }
+    tokendb.flush()?;
+    if replayed > 0 {
+        println!("Replayed {} placements from the journal", replayed);
+    }
+    Ok(())
+}
+
+// History records only keep the time in milliseconds
+fn history_key(
+    timestamp: SystemTime,
+    uid: &str,
+    x: u32,
+    y: u32,
+    color: grid::CellData,
+) -> (u128, String, u32, u32, [u8; 4]) {
+    let millis = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis();
+    (
+        millis,
+        uid.to_string(),
+        x,
+        y,
+        [color.r, color.g, color.b, color.a],
+    )
+}
+
+// Returns the number of replayed placements
+fn replay_entries(
+    grid: &mut grid::Grid,
+    history: &mut history::History,
+    history_path: &Path,
+    journal: &[(u64, tokendb::JournalEntry)],
+) -> Result<usize> {
+    let mut unlogged: HashMap<_, Vec<usize>> = HashMap::new();
+    for (i, (_, entry)) in journal.iter().enumerate() {
+        unlogged
+            .entry(history_key(
+                entry.timestamp,
+                &entry.uid,
+                entry.x,
+                entry.y,
+                entry.color,
+            ))
+            .or_default()
+            .push(i);
+    }
+    let cells: HashSet<(u32, u32)> = journal.iter().map(|(_, e)| (e.x, e.y)).collect();
+
+    let mut logged = vec![false; journal.len()];
+    let mut history_colors = HashMap::new();
+    for placement in history::History::read(history_path)? {
+        let placement = placement?;
+        let key = history_key(
+            placement.timestamp,
+            &placement.uid,
+            placement.x,
+            placement.y,
+            placement.color,
+        );
+        if let Some(i) = unlogged.get_mut(&key).and_then(Vec::pop) {
+            logged[i] = true;
+        }
+        if cells.contains(&(placement.x, placement.y)) {
+            history_colors.insert((placement.x, placement.y), placement.color);
+        }
+    }
+
+    let mut replayed = 0;
+    for ((_, entry), logged) in journal.iter().zip(logged) {
+        if logged {
+            println!(
+                "Journaled placement of user {} at ({}, {}) is already in the history",
+                entry.uid, entry.x, entry.y
+            );
+        } else if let Err(e) = grid.get_cell(entry.x as usize, entry.y as usize) {
+            // The grid may have been resized since
+            eprintln!("Dropping journaled placement of user {}: {}", entry.uid, e);
+        } else {
+            apply_placement(grid, history, entry)
+                .context("Failed to replay journaled placement")?;
+            history_colors.insert((entry.x, entry.y), entry.color);
+            replayed += 1;
+        }
+    }
+
+    for (&(x, y), &color) in &history_colors {
+        let (x, y) = (x as usize, y as usize);
+        if grid.get_cell(x, y).is_ok_and(|cell| cell != color) {
+            println!(
+                "Cell ({}, {}) missed its latest placement, restoring it from the history",
+                x, y
+            );
+            grid.write_cell(x, y, color)?;
+        }
+    }
+    Ok(replayed)
+}
+
+// Writes the charged placements to disk in batches: the placements that arrive while a batch is
+// being written share the next token database flush and grid sync. The disk I/O runs on the
+// blocking thread pool, so that it doesn't stall the runtime
+async fn run_committer(
+    state: &'static GlobalState,
+    mut pending_placements: mpsc::UnboundedReceiver<PendingPlacement>,
+) {
+    while let Some(first_placement) = pending_placements.recv().await {
+        let mut entries = Vec::new();
+        let mut token_uses = Vec::new();
+        let mut waiters = Vec::new();
+        let mut next_placement = Some(first_placement);
+        while let Some(placement) = next_placement {
+            entries.push(placement.entry);
+            token_uses.push(placement.token_use);
+            waiters.push(placement.done);
+            next_placement = pending_placements.try_recv().ok();
+        }
+
+        // The charges and journal entries have to be on disk before the placements reach the grid
+        let flushed = tokio::task::spawn_blocking(|| state.tokendb.flush())
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        let mut grid = state.grid.write().await;
+        let mut history = state.history.lock().await;
+        let committed = tokio::task::spawn_blocking(move || {
+            let results = match flushed {
+                Ok(()) => write_placements(&mut grid, &mut history, &entries),
+                Err(e) => entries.iter().map(|_| Err(anyhow!("{:#}", e))).collect(),
+            };
+            drop(history);
+            drop(grid);
+            settle_placements(&state.tokendb, &token_uses, &results);
+            (entries, results)
+        })
+        .await;
+
+        let (entries, results) = match committed {
+            Ok(committed) => committed,
+            Err(e) => {
+                // The waiters see their placements dropped. The placements stay in the journal
+                eprintln!("Failed to commit placements: {:?}", e);
+                continue;
+            }
+        };
+        for ((entry, result), done) in entries.iter().zip(results).zip(waiters) {
+            if result.is_ok() {
+                state.broadcast_grid_update(entry.x as usize, entry.y as usize, entry.color);
+            }
+            // The placer may have disconnected in the meantime
+            let _ = done.send(result);
+        }
+    }
+}
+
+// Collects the updates made during a tick, drops all but the last write to each cell, and sends
+// them to the clients in one go, so that bursts of placements don't turn into a flood of frames
+async fn run_broadcaster(
+    state: &'static GlobalState,
+    mut grid_updates: mpsc::UnboundedReceiver<(u32, u32, grid::CellData)>,
+) {
+    while let Some(first_update) = grid_updates.recv().await {
+        tokio::time::sleep(BROADCAST_TICK).await;
+
+        let mut batch = vec![first_update];
+        let mut index_by_cell = HashMap::from([((first_update.0, first_update.1), 0)]);
+        while let Ok((x, y, cell)) = grid_updates.try_recv() {
+            match index_by_cell.entry((x, y)) {
+                Entry::Occupied(entry) => batch[*entry.get()].2 = cell,
+                Entry::Vacant(entry) => {
+                    entry.insert(batch.len());
+                    batch.push((x, y, cell));
+                }
+            }
+        }
+
+        // Hidden cells can still change, e.g. by a wipe, but that must not be visible yet
+        let regions = &state.config().regions;
+        let now = SystemTime::now();
+        batch.retain(|&(x, y, _)| {
+            regions::state_at(regions, x, y, now) != config::RegionState::Hidden
+        });
+
+        if !batch.is_empty() {
+            state.send_grid_updates(&batch).await;
+        }
+    }
+}
+
+// Sends the contents of hidden regions to the clients once their reveal time comes
+async fn run_revealer(state: &'static GlobalState) {
+    let mut last_check = SystemTime::now();
+    let mut interval = tokio::time::interval(REVEAL_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now();
+        let config = state.config();
+
+        let grid = state.grid.read().await;
+        for region in &config.regions {
+            match region.reveal_time() {
+                Some(reveal_time) if last_check < reveal_time && reveal_time <= now => {}
+                _ => continue,
+            }
+            for y in region.y..region.y.saturating_add(region.height).min(grid.height()) {
+                for x in region.x..region.x.saturating_add(region.width).min(grid.width()) {
+                    // Overlapping regions may still keep the cell hidden or have revealed it
+                    // already
+                    if regions::state_at(&config.regions, x, y, last_check)
+                        == config::RegionState::Hidden
+                        && regions::state_at(&config.regions, x, y, now)
+                            != config::RegionState::Hidden
+                    {
+                        if let Ok(cell) = grid.get_cell(x as usize, y as usize) {
+                            state.broadcast_grid_update(x as usize, y as usize, cell);
+                        }
+                    }
+                }
+            }
+        }
+        drop(grid);
+
+        last_check = now;
+    }
 }
 
 #[derive(FromForm)]
 struct GetTokenForm<'r> {
     login: &'r str,
     password: &'r str,
-    group: usize,
+    group: Option<usize>,
 }
 
 #[derive(FromForm)]
 struct SetColorForm<'r> {
-    token: &'r str,
+    token: Option<&'r str>,
     row: usize,
     column: usize,
-    color: &'r str,
+    // Exactly one of these must be set: palette boards take an index, others a color
+    color: Option<&'r str>,
+    index: Option<u8>,
 }
 
 #[rocket::post("/get_token", data = "<info>")]
-async fn get_token(state: &State<&'static GlobalState>, info: Form<GetTokenForm<'_>>) -> String {
-    let check_result = match ejudge::check_account(info.login, info.password, info.group).await {
-        Ok(x) => x,
+async fn get_token(
+    state: &State<&'static GlobalState>,
+    cookies: &CookieJar<'_>,
+    info: Form<GetTokenForm<'_>>,
+) -> String {
+    let credentials = auth::Credentials {
+        login: info.login,
+        password: info.password,
+        group: info.group,
+    };
+    let uid = match state.auth_provider().authenticate(&credentials).await {
+        Ok(Some(uid)) => uid,
+        Ok(None) => {
+            return "Invalid credentials".to_string();
+        }
         Err(e) => {
             return format!("Unexpected error: {:?}", e);
         }
     };
-    if !check_result {
-        return "Invalid credentials".to_string();
+    log_in(state, cookies, &uid)
+}
+
+// Starts a session for an authenticated user and issues them a token, unless they already have
+// one, for clients that can't use cookies
+fn log_in(state: &GlobalState, cookies: &CookieJar<'_>, uid: &str) -> String {
+    if let Err(e) = session::start(cookies, uid, state.config().session_lifetime()) {
+        return format!("Login failed: {}", e);
     }
-    let token = (*state)
+    match state
         .tokendb
-        .create_token_for_user(&format!("ejudge/{}", info.login));
-    match token {
-        Ok(token) => format!("Your token: {}", token.to_string()),
-        Err(e) => e.to_string(),
+        .create_token_for_user(uid, state.config().token_bytes)
+    {
+        Ok(token) => format!("You are logged in. Your token for other clients: {}", token),
+        Err(e) => format!("You are logged in. {}", e),
+    }
+}
+
+#[rocket::post("/logout")]
+fn logout(cookies: &CookieJar<'_>) -> Redirect {
+    session::end(cookies);
+    Redirect::to("/")
+}
+
+#[rocket::get("/oidc/login")]
+async fn oidc_login(
+    state: &State<&'static GlobalState>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, String> {
+    let oidc = state
+        .oidc()
+        .ok_or_else(|| "OpenID Connect login is not configured".to_string())?;
+    match oidc.start_login().await {
+        Ok((url, login)) => {
+            login.save(cookies);
+            Ok(Redirect::to(url))
+        }
+        Err(e) => Err(format!("Unexpected error: {:#}", e)),
+    }
+}
+
+#[rocket::get("/oidc/callback?<code>&<state>&<error>")]
+async fn oidc_callback(
+    global_state: &State<&'static GlobalState>,
+    cookies: &CookieJar<'_>,
+    code: Option<&str>,
+    state: Option<&str>,
+    error: Option<&str>,
+) -> String {
+    let oidc = match global_state.oidc() {
+        Some(oidc) => oidc,
+        None => return "OpenID Connect login is not configured".to_string(),
+    };
+    if let Some(error) = error {
+        return format!("Login failed: {}", error);
     }
+    let (code, state) = match (code, state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return "Login failed: the provider did not return an authorization code".to_string(),
+    };
+
+    let login = oidc::PendingLogin::take(cookies);
+    let uid = match oidc.finish_login(login, code, state).await {
+        Ok(uid) => uid,
+        Err(e) => return format!("Login failed: {:#}", e),
+    };
+    log_in(global_state, cookies, &uid)
 }
 
 fn parse_color(mut color: &str) -> Result<(u8, u8, u8)> {
-    if color.chars().next() == Some('#') {
+    if color.starts_with('#') {
         color = &color[1..];
     }
     if color.len() != 6 {
@@ -99,154 +910,418 @@ fn parse_color(mut color: &str) -> Result<(u8, u8, u8)> {
 }
 
 #[rocket::post("/set_color", data = "<info>")]
-async fn set_color(state: &State<&'static GlobalState>, info: Form<SetColorForm<'_>>) -> String {
-    let token = tokendb::Token::from_string(info.token);
-
-    let (r, g, b) = match parse_color(info.color) {
-        Ok(color) => color,
-        Err(e) => return e.to_string(),
+async fn set_color(
+    state: &State<&'static GlobalState>,
+    session: Option<session::Session>,
+    info: Form<SetColorForm<'_>>,
+) -> String {
+    let color = match (info.color, info.index) {
+        (Some(color), None) => match parse_color(color) {
+            Ok((r, g, b)) => grid::Color::Rgba(grid::CellData { r, g, b, a: 255 }),
+            Err(e) => return e.to_string(),
+        },
+        (None, Some(index)) => grid::Color::Index(index),
+        _ => return "Exactly one of 'color' and 'index' must be set".to_string(),
     };
 
-    if let Err(e) = state.tokendb.try_use_token(token, get_cooldown_period()) {
-        return e.to_string();
-    }
-
+    let token = info.token.filter(|token| !token.is_empty());
     let x = info.column;
     let y = info.row;
-    let a = 255;
 
-    let cell = grid::CellData { r, g, b, a };
-
-    if let Err(e) = state.grid.write().await.set_cell(x, y, cell) {
+    if let Err(e) = state.place_cell(token, session.as_ref(), x, y, color).await {
         return e.to_string();
     }
 
-    state.broadcast_grid_update(x, y, cell).await;
-
     "OK".to_string()
 }
 
-async fn handle_ws_message(state: &'static GlobalState, msg: Message) -> Result<()> {
-    match msg {
-        Message::Text(ref s) => {
-            let parts: Vec<&str> = s.split(" ").collect();
-            if parts.len() != 8 || parts[0] != "set" {
-                bail!("Invalid command syntax: must be 'set <token> <x> <y> <r> <g> <b> <a>'");
-            }
-
-            let token = tokendb::Token::from_string(&parts[1]);
+#[rocket::get("/board.png?<scale>&<x>&<y>&<width>&<height>")]
+async fn board_png(
+    state: &State<&'static GlobalState>,
+    scale: Option<u32>,
+    x: Option<u32>,
+    y: Option<u32>,
+    width: Option<u32>,
+    height: Option<u32>,
+) -> Result<(ContentType, Vec<u8>), BadRequest<String>> {
+    let grid = state.grid.read().await;
+    let grid_width = grid.width();
+    let grid_height = grid.height();
+    let data = state.visible_grid_rgba(&grid);
+    drop(grid);
 
-            let mut nums = [0usize; 6];
-            for i in 0..6 {
-                match parts[2 + i].parse() {
-                    Ok(num) => nums[i] = num,
-                    Err(e) => {
-                        bail!("Invalid command syntax: not a number: {}", e);
-                    }
-                }
-            }
-            if nums[2..6].iter().max().unwrap() > &255 {
-                bail!(
-                    "Invalid command syntax: color components must be in range 0..255 (inclusive)"
-                );
-            }
+    let crop = image::Crop::resolve(x, y, width, height, grid_width, grid_height)
+        .map_err(|e| BadRequest(e.to_string()))?;
 
-            state.tokendb.try_use_token(token, get_cooldown_period())?;
+    let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        image::export_png(
+            &mut png,
+            &data,
+            grid_width,
+            &crop,
+            scale.unwrap_or(1),
+            image::MAX_HTTP_EXPORT_PIXELS,
+        )?;
+        Ok(png)
+    })
+    .await
+    .map_err(|e| BadRequest(e.to_string()))?
+    .map_err(|e| BadRequest(e.to_string()))?;
 
-            let x: usize = nums[0];
-            let y: usize = nums[1];
-            let r: u8 = nums[2] as u8;
-            let g: u8 = nums[3] as u8;
-            let b: u8 = nums[4] as u8;
-            let a: u8 = nums[5] as u8;
+    Ok((ContentType::PNG, png))
+}
 
-            let cell = grid::CellData { r, g, b, a };
+fn timestamp_from_secs(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
 
-            state.grid.write().await.set_cell(x, y, cell)?;
+async fn handle_ws_message(
+    state: &'static GlobalState,
+    protocol: protocol::Protocol,
+    session: Option<&session::Session>,
+    sender: &mpsc::Sender<Message>,
+    msg: Message,
+) -> Result<()> {
+    let command = protocol.decode_command(&msg)?;
 
-            state.broadcast_grid_update(x, y, cell).await;
+    let (cell, shadowbanned) = state
+        .place_cell(
+            command.token.as_deref(),
+            session,
+            command.x,
+            command.y,
+            command.color,
+        )
+        .await?;
 
-            Ok(())
-        }
-        _ => {
-            bail!("Invalid message: must be text");
+    if shadowbanned {
+        // Only the placer sees the update, as if it was broadcast
+        let update = (command.x as u32, command.y as u32, cell);
+        for message in protocol.encode_updates(&[update]) {
+            sender.send(message).await.context("Connection is closed")?;
         }
     }
+
+    Ok(())
 }
 
-async fn handle_ws_connection(
+#[rocket::get("/ws")]
+fn ws(
+    state: &State<&'static GlobalState>,
+    handshake: websocket::Handshake,
+    session: Option<session::Session>,
+    client_ip: Option<IpAddr>,
+) -> websocket::Upgrade {
+    let state: &'static GlobalState = state.inner();
+    let negotiated = protocol::Protocol::negotiate(handshake.requested_protocols());
+    let protocol = negotiated.unwrap_or(protocol::Protocol::Text);
+    let peer = client_ip.map_or("unknown".to_string(), |ip| ip.to_string());
+    handshake.accept(negotiated.map(|p| p.name()), move |ws_stream| {
+        Box::pin(async move {
+            if let Err(e) = handle_ws_connection(state, ws_stream, protocol, session, peer).await {
+                eprintln!("Websocket error: {:?}", e);
+            }
+        })
+    })
+}
+
+// The type of the handshake callback's error is given by tungstenite
+#[allow(clippy::result_large_err)]
+async fn accept_standalone_ws_connection(
     state: &'static GlobalState,
     raw_stream: TcpStream,
     addr: SocketAddr,
 ) -> Result<()> {
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .context("Handshake failed")?;
+    let mut protocol = protocol::Protocol::Text;
+    let ws_stream = tokio_tungstenite::accept_hdr_async(
+        raw_stream,
+        |request: &Request, mut response: Response| {
+            let requested = request
+                .headers()
+                .get("Sec-WebSocket-Protocol")
+                .and_then(|value| value.to_str().ok());
+            if let Some(negotiated) = protocol::Protocol::negotiate(requested) {
+                protocol = negotiated;
+                response.headers_mut().insert(
+                    "Sec-WebSocket-Protocol",
+                    HeaderValue::from_static(negotiated.name()),
+                );
+            }
+            Ok(response)
+        },
+    )
+    .await
+    .context("Handshake failed")?;
+
+    // Session cookies are only understood by /ws, clients of the standalone server have to send
+    // tokens explicitly
+    handle_ws_connection(state, ws_stream, protocol, None, addr.to_string()).await
+}
+
+async fn handle_ws_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &'static GlobalState,
+    ws_stream: WebSocketStream<S>,
+    protocol: protocol::Protocol,
+    session: Option<session::Session>,
+    peer: String,
+) -> Result<()> {
+    println!("WS connection from {} using {}", peer, protocol.name());
 
-    println!("WS connection from {}", addr);
+    let (outgoing, incoming) = ws_stream.split();
 
-    let (mut outgoing, mut incoming) = ws_stream.split();
+    let (sender, receiver) = mpsc::channel(WS_QUEUE_LIMIT);
+    let dropped = Arc::new(Notify::new());
+    let id = state.next_ws_connection_id.fetch_add(1, Ordering::Relaxed);
 
+    // The connection is registered before the grid lock is released, so that every update and
+    // resize made after the snapshot reaches the client, and in order
     let grid = state.grid.read().await;
-    let grid_width = grid.width();
-    let grid_height = grid.height();
-    let grid_data = grid.get_data_serialized();
+    let grid_data = state.visible_grid_data(&grid);
+    for message in protocol.encode_grid(grid.width(), grid.height(), grid.palette(), grid_data) {
+        sender
+            .try_send(message)
+            .context("Failed to queue initial grid data")?;
+    }
+    state.ws_connections.write().await.insert(
+        id,
+        WsConnection {
+            protocol,
+            outgoing: sender.clone(),
+            dropped: dropped.clone(),
+        },
+    );
     drop(grid);
 
-    outgoing
-        .send(Message::Text(format!(
-            "grid {} {}",
-            grid_width, grid_height
-        )))
-        .await
-        .context("Failed to send initial grid data")?;
-    outgoing
-        .send(Message::Binary(grid_data))
-        .await
-        .context("Failed to send initial grid data")?;
-
-    let outgoing = Arc::new(Mutex::new(outgoing));
-
-    state
-        .ws_connections
-        .write()
-        .await
-        .insert(addr, outgoing.clone());
-
-    while let Some(msg) = incoming.try_next().await? {
-        if let Err(e) = handle_ws_message(state, msg).await {
+    let result = tokio::select! {
+        result = ws_read_loop(state, protocol, session, incoming, sender) => result,
+        result = ws_write_loop(outgoing, receiver) => result,
+        _ = dropped.notified() => Err(anyhow!("Client is too slow to receive updates, dropped it")),
+    };
+
+    state.ws_connections.write().await.remove(&id);
+
+    println!("{} disconnected", &peer);
+
+    result
+}
+
+async fn ws_read_loop(
+    state: &'static GlobalState,
+    protocol: protocol::Protocol,
+    session: Option<session::Session>,
+    mut incoming: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    sender: mpsc::Sender<Message>,
+) -> Result<()> {
+    loop {
+        let msg = match tokio::time::timeout(WS_IDLE_TIMEOUT, incoming.next()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => return Ok(()),
+            Err(_) => bail!(
+                "Client was idle for {:?}, closed the connection",
+                WS_IDLE_TIMEOUT
+            ),
+        };
+
+        // Pings are answered by tungstenite itself, pongs only have to reset the idle timeout
+        if msg.is_ping() || msg.is_pong() {
+            continue;
+        }
+        if msg.is_close() {
+            return Ok(());
+        }
+
+        if let Err(e) = handle_ws_message(state, protocol, session.as_ref(), &sender, msg).await {
             eprintln!("{}", e);
-            outgoing
-                .lock()
+            sender
+                .send(protocol.encode_error(&e))
                 .await
-                .send(Message::Text(format!("error {}", e)))
-                .await?;
+                .context("Connection is closed")?;
         }
     }
+}
 
-    println!("{} disconnected", &addr);
-
-    Ok(())
+async fn ws_write_loop(
+    mut outgoing: impl Sink<Message, Error = tungstenite::Error> + Unpin,
+    mut receiver: mpsc::Receiver<Message>,
+) -> Result<()> {
+    let mut ping_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + WS_PING_INTERVAL,
+        WS_PING_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Some(message) => outgoing.send(message).await?,
+                None => return Ok(()),
+            },
+            _ = ping_interval.tick() => outgoing.send(Message::Ping(Vec::new())).await?,
+        }
+    }
 }
 
 async fn start_http_server(state: &'static GlobalState) -> Result<()> {
-    rocket::build()
-        .mount("/", routes![get_token, set_color])
-        .mount("/", FileServer::from("static"))
+    let config = state.config();
+
+    let mut figment = rocket::Config::figment();
+    if let Some(address) = config.address {
+        figment = figment.merge(("address", address));
+    }
+    if let Some(port) = config.port {
+        figment = figment.merge(("port", port));
+    }
+    if let Some(ref secret_key) = config.secret_key {
+        figment = figment.merge(("secret_key", secret_key));
+    } else if figment
+        .extract_inner::<rocket::config::SecretKey>("secret_key")
+        .map_or(true, |key| key.is_zero())
+    {
+        figment = figment.merge(("secret_key", load_secret_key(state.dir_path.as_ref())?));
+    }
+
+    // Old deployments may still route websocket traffic to a separate port
+    if let Some(ref address) = config.standalone_ws_address {
+        tokio::spawn(start_ws_server(state, address.clone()));
+    }
+
+    rocket::custom(figment)
+        .mount(
+            "/",
+            routes![
+                get_token,
+                oidc_login,
+                oidc_callback,
+                logout,
+                set_color,
+                board_png,
+                ws
+            ],
+        )
+        .mount("/admin", admin::routes())
+        .register("/admin", admin::catchers())
+        .mount("/", FileServer::from(&config.static_dir))
         .manage(state)
         .launch()
         .await?;
     Ok(())
 }
 
-async fn start_ws_server(state: &'static GlobalState) {
-    async fn go(state: &'static GlobalState) -> Result<()> {
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:9000")
+// Returns the header and the data of the grid with the new size
+fn resized_grid(
+    grid: &grid::Grid,
+    width: u32,
+    height: u32,
+    force: bool,
+) -> Result<(grid::Header, Vec<Vec<grid::CellData>>)> {
+    if width == 0 || height == 0 {
+        bail!("The grid must be at least 1x1");
+    }
+    if (width < grid.width() || height < grid.height()) && !force {
+        bail!("The new size is smaller than the current grid size. Add '--force' (or 'force=true' in the admin API) to cut the bottom right corner");
+    }
+
+    let header = grid::Header {
+        width,
+        height,
+        ..grid.header().clone()
+    };
+    Ok((header, grid.resized_data(width, height)?))
+}
+
+// Writes the resized grid next to the grid data file and moves it into place. Returns the new grid
+fn write_resized_grid(
+    dir_path: &str,
+    header: &grid::Header,
+    data: &[Vec<grid::CellData>],
+) -> Result<grid::Grid> {
+    let tmp_path = format!("{}/grid.tmp", dir_path);
+    let path = format!("{}/grid", dir_path);
+    grid::Grid::create_file_with_data(tmp_path.as_ref(), header, data)?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    grid::Grid::open(path.as_ref()).context("Failed to load resized grid data file")
+}
+
+// Returns the key from <dir>/secret_key, generating it on first use, so that sessions survive
+// restarts without any configuration
+fn load_secret_key(dir_path: &Path) -> Result<String> {
+    let path = dir_path.join("secret_key");
+    match std::fs::read_to_string(&path) {
+        Ok(key) => return Ok(key.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("Failed to read secret key"),
+    }
+
+    let mut buf = [0u8; 32];
+    OsRng
+        .try_fill_bytes(&mut buf)
+        .context("Failed to generate secret key")?;
+    let mut key = String::with_capacity(buf.len() * 2);
+    for byte in buf {
+        write!(key, "{:02x}", byte).unwrap();
+    }
+    // Only the owner may read the key
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .context("Failed to save secret key")?;
+    std::io::Write::write_all(&mut file, key.as_bytes()).context("Failed to save secret key")?;
+    Ok(key)
+}
+
+// Listen addresses and the static directory are only read on start, everything else is taken
+// from the latest configuration whenever it is needed
+async fn reload_config_on_sighup(state: &'static GlobalState) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!(
+                "Failed to listen for SIGHUP, configuration reload is disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let reloaded = config::Config::load(state.dir_path.as_ref()).and_then(|config| {
+            let auth_provider = auth::from_config(&config.auth, state.dir_path.as_ref())?;
+            Ok((config, auth_provider))
+        });
+        match reloaded {
+            Ok((config, auth_provider)) => {
+                *state.oidc.write().unwrap() = config
+                    .oidc
+                    .clone()
+                    .map(|oidc| Arc::new(oidc::OidcClient::new(oidc)));
+                let config = Arc::new(config);
+                let old_config =
+                    std::mem::replace(&mut *state.config.write().unwrap(), config.clone());
+                *state.auth_provider.write().unwrap() = auth_provider;
+                println!("Reloaded configuration");
+                state
+                    .push_region_changes(&old_config.regions, &config.regions)
+                    .await;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to reload configuration, keeping the old one: {:?}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+async fn start_ws_server(state: &'static GlobalState, address: String) {
+    async fn go(state: &'static GlobalState, address: &str) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(address)
             .await
-            .context("Failed to bind to 0.0.0.0:9000")?;
+            .with_context(|| format!("Failed to bind to {}", address))?;
         while let Ok((stream, addr)) = listener.accept().await {
             tokio::spawn(async move {
-                if let Err(e) = handle_ws_connection(state, stream, addr).await {
+                if let Err(e) = accept_standalone_ws_connection(state, stream, addr).await {
                     eprintln!("Websocket error: {:?}", e);
                 }
             });
@@ -254,7 +1329,7 @@ async fn start_ws_server(state: &'static GlobalState) {
         Ok(())
     }
 
-    match go(state).await {
+    match go(state, &address).await {
         Ok(()) => {
             panic!("WS server stopped");
         }
@@ -266,19 +1341,55 @@ async fn start_ws_server(state: &'static GlobalState) {
 }
 
 enum Command {
-    Init(String, u32, u32),
+    Init(String, grid::Header),
     Serve(String),
     AddToken(String, String, String),
+    RevokeToken(String, String),
+    RotateToken(String, String),
+    ListTokens(String),
+    SetUserStatus(String, String, tokendb::UserStatus),
+    SetRole(String, String, Option<tokendb::Role>),
     Resize(String, u32, u32, bool),
+    History(String),
+    Timelapse(String, String, timelapse::Step),
+    Rollback(String, rollback::Filter),
+    Export(String, String, u32, Option<[u32; 4]>),
+    Import(String, String, i64, i64, bool),
+    Fsck(String),
+    Restore(String, String),
 }
 
+// Shown when the command is missing or unknown. See README.md for the details of each command
+const USAGE: &str = "Usage:
+  rplace init <data_dir> <width> <height> [--palette <colors>] [--background <color>]
+  rplace serve <data_dir>
+  rplace add-token <data_dir> <token> <uid>
+  rplace rotate-token <data_dir> <uid>
+  rplace revoke-token <data_dir> <token>
+  rplace list-tokens <data_dir>
+  rplace ban <data_dir> <uid> [--until <unix_time> | --for <seconds>]
+  rplace shadowban <data_dir> <uid>
+  rplace unban <data_dir> <uid>
+  rplace set-role <data_dir> <uid> <admin|moderator|none>
+  rplace resize <data_dir> <width> <height> [--force]
+  rplace history <data_dir>
+  rplace timelapse <data_dir> <out_dir> [--placements <count> | --interval <seconds>]
+  rplace rollback <data_dir> [--uid <uid>] [--since <unix_time>] [--until <unix_time>]
+  rplace export <data_dir> <file.png> [--scale <cell_size>] [--crop <x> <y> <width> <height>]
+  rplace import <data_dir> <image.png> [--x <x>] [--y <y>] [--blend]
+  rplace restore <data_dir> <snapshot>
+  rplace fsck <data_dir>";
+
 fn get_command() -> Result<Command> {
     let mut args = std::env::args();
     args.next().unwrap();
 
-    let command = args.next().context(
-        "The first CLI argument must be the command name: 'serve', 'init', or 'add-token'",
-    )?;
+    let command = args.next().with_context(|| {
+        format!(
+            "The first CLI argument must be the command name\n\n{}",
+            USAGE
+        )
+    })?;
 
     match command.as_ref() {
         "init" => {
@@ -293,7 +1404,30 @@ fn get_command() -> Result<Command> {
                 .context("'rplace init' expects the height of the grid as the third argument")?
                 .parse()
                 .context("Invalid height")?;
-            Ok(Command::Init(dir_path, width, height))
+            let mut palette = None;
+            let mut background = None;
+            while let Some(arg) = args.next() {
+                match arg.as_ref() {
+                    "--palette" => {
+                        palette = Some(grid::parse_palette(&args.next().context(
+                            "'--palette' expects a comma-separated list of colors, e.g. '#ffffff,#000000'",
+                        )?)?);
+                    }
+                    "--background" => {
+                        background = Some(grid::CellData::parse(
+                            &args
+                                .next()
+                                .context("'--background' expects a color, e.g. '#ffffff'")?,
+                        )?);
+                    }
+                    _ => bail!(
+                        "Unknown option {}: 'rplace init' accepts '--palette <colors>' and '--background <color>'",
+                        arg
+                    ),
+                }
+            }
+            let header = grid::Header::new(width, height, palette, background)?;
+            Ok(Command::Init(dir_path, header))
         }
         "serve" => {
             let dir_path = args.next().context("'rplace serve' expects the path to the directory for permanent storage as an argument")?;
@@ -309,6 +1443,92 @@ fn get_command() -> Result<Command> {
                 .context("'rplace add-token' expects the UID as the third argument")?;
             Ok(Command::AddToken(dir_path, token, uid))
         }
+        "revoke-token" => {
+            let dir_path = args.next().context("'rplace revoke-token' expects the path to the directory for permanent storage as the first argument")?;
+            let token = args
+                .next()
+                .context("'rplace revoke-token' expects the token as the second argument")?;
+            Ok(Command::RevokeToken(dir_path, token))
+        }
+        "rotate-token" => {
+            let dir_path = args.next().context("'rplace rotate-token' expects the path to the directory for permanent storage as the first argument")?;
+            let uid = args
+                .next()
+                .context("'rplace rotate-token' expects the UID as the second argument")?;
+            Ok(Command::RotateToken(dir_path, uid))
+        }
+        "list-tokens" => {
+            let dir_path = args.next().context("'rplace list-tokens' expects the path to the directory for permanent storage as an argument")?;
+            Ok(Command::ListTokens(dir_path))
+        }
+        "ban" => {
+            let dir_path = args.next().context("'rplace ban' expects the path to the directory for permanent storage as the first argument")?;
+            let uid = args
+                .next()
+                .context("'rplace ban' expects the UID as the second argument")?;
+            let until = match args.next().as_deref() {
+                None => None,
+                Some("--until") => {
+                    let secs: u64 = args
+                        .next()
+                        .context("'--until' expects a UNIX timestamp")?
+                        .parse()
+                        .context("Invalid timestamp")?;
+                    Some(timestamp_from_secs(secs))
+                }
+                Some("--for") => {
+                    let seconds: u64 = args
+                        .next()
+                        .context("'--for' expects the duration of the ban in seconds")?
+                        .parse()
+                        .context("Invalid duration")?;
+                    Some(
+                        SystemTime::now()
+                            .checked_add(Duration::from_secs(seconds))
+                            .context("Invalid duration")?,
+                    )
+                }
+                Some(arg) => bail!(
+                    "Unknown option {}: 'rplace ban' accepts either '--until <unix_time>' or '--for <seconds>'",
+                    arg
+                ),
+            };
+            Ok(Command::SetUserStatus(
+                dir_path,
+                uid,
+                tokendb::UserStatus::Banned(until),
+            ))
+        }
+        "set-role" => {
+            let dir_path = args.next().context("'rplace set-role' expects the path to the directory for permanent storage as the first argument")?;
+            let uid = args
+                .next()
+                .context("'rplace set-role' expects the UID as the second argument")?;
+            let role = match args
+                .next()
+                .context("'rplace set-role' expects the role as the third argument: 'admin', 'moderator', or 'none'")?
+                .as_ref()
+            {
+                "none" => None,
+                name => Some(tokendb::Role::from_name(name)?),
+            };
+            Ok(Command::SetRole(dir_path, uid, role))
+        }
+        "shadowban" | "unban" => {
+            let dir_path = args.next().with_context(|| format!("'rplace {}' expects the path to the directory for permanent storage as the first argument", command))?;
+            let uid = args.next().with_context(|| {
+                format!(
+                    "'rplace {}' expects the UID as the second argument",
+                    command
+                )
+            })?;
+            let status = if command == "shadowban" {
+                tokendb::UserStatus::Shadowbanned
+            } else {
+                tokendb::UserStatus::Active
+            };
+            Ok(Command::SetUserStatus(dir_path, uid, status))
+        }
         "resize" => {
             let dir_path = args.next().context("'rplace resize' expects the path to the directory for permanent storage as the first argument")?;
             let width: u32 = args
@@ -328,47 +1548,221 @@ fn get_command() -> Result<Command> {
             let force = args.next() == Some("--force".to_string());
             Ok(Command::Resize(dir_path, width, height, force))
         }
-        _ => bail!(
-            "Unknown CLI command: {}. Run rplace without arguments to see some help",
-            command
-        ),
+        "history" => {
+            let dir_path = args.next().context("'rplace history' expects the path to the directory for permanent storage as an argument")?;
+            Ok(Command::History(dir_path))
+        }
+        "timelapse" => {
+            let dir_path = args.next().context("'rplace timelapse' expects the path to the directory for permanent storage as the first argument")?;
+            let out_dir = args.next().context(
+                "'rplace timelapse' expects the path to the output directory as the second argument",
+            )?;
+            let step = match args.next().as_deref() {
+                None => timelapse::Step::Placements(100),
+                Some("--placements") => {
+                    let count: u64 = args
+                        .next()
+                        .context("'--placements' expects the number of placements per frame")?
+                        .parse()
+                        .context("Invalid number of placements per frame")?;
+                    if count == 0 {
+                        bail!("The number of placements per frame must be positive");
+                    }
+                    timelapse::Step::Placements(count)
+                }
+                Some("--interval") => {
+                    let seconds: f64 = args
+                        .next()
+                        .context("'--interval' expects the number of seconds per frame")?
+                        .parse()
+                        .context("Invalid number of seconds per frame")?;
+                    if seconds <= 0.0 {
+                        bail!("The number of seconds per frame must be positive");
+                    }
+                    timelapse::Step::Interval(
+                        Duration::try_from_secs_f64(seconds).context("Invalid interval")?,
+                    )
+                }
+                Some(arg) => bail!(
+                    "Unknown option {}: 'rplace timelapse' accepts either '--placements <count>' or '--interval <seconds>'",
+                    arg
+                ),
+            };
+            Ok(Command::Timelapse(dir_path, out_dir, step))
+        }
+        "rollback" => {
+            let dir_path = args.next().context("'rplace rollback' expects the path to the directory for permanent storage as the first argument")?;
+            let mut filter = rollback::Filter {
+                uid: None,
+                since: None,
+                until: None,
+            };
+            while let Some(arg) = args.next() {
+                match arg.as_ref() {
+                    "--uid" => {
+                        filter.uid = Some(args.next().context("'--uid' expects a UID")?);
+                    }
+                    "--since" | "--until" => {
+                        let secs: u64 = args
+                            .next()
+                            .with_context(|| format!("'{}' expects a UNIX timestamp", arg))?
+                            .parse()
+                            .context("Invalid timestamp")?;
+                        if arg == "--since" {
+                            filter.since = Some(timestamp_from_secs(secs));
+                        } else {
+                            filter.until = Some(timestamp_from_secs(secs));
+                        }
+                    }
+                    _ => bail!("Unknown option {} for 'rplace rollback'", arg),
+                }
+            }
+            if filter.is_empty() {
+                bail!("'rplace rollback' expects at least one of '--uid <uid>', '--since <timestamp>', and '--until <timestamp>'");
+            }
+            Ok(Command::Rollback(dir_path, filter))
+        }
+        "export" => {
+            let dir_path = args.next().context("'rplace export' expects the path to the directory for permanent storage as the first argument")?;
+            let out_path = args.next().context(
+                "'rplace export' expects the path to the PNG file as the second argument",
+            )?;
+            let mut scale = 1;
+            let mut crop = None;
+            while let Some(arg) = args.next() {
+                match arg.as_ref() {
+                    "--scale" => {
+                        scale = args
+                            .next()
+                            .context("'--scale' expects the size of a cell in pixels")?
+                            .parse()
+                            .context("Invalid scale")?;
+                    }
+                    "--crop" => {
+                        let mut rect = [0u32; 4];
+                        for value in &mut rect {
+                            *value = args
+                                .next()
+                                .context("'--crop' expects four numbers: <x> <y> <width> <height>")?
+                                .parse()
+                                .context("Invalid crop rectangle")?;
+                        }
+                        crop = Some(rect);
+                    }
+                    _ => bail!("Unknown option {} for 'rplace export'", arg),
+                }
+            }
+            Ok(Command::Export(dir_path, out_path, scale, crop))
+        }
+        "import" => {
+            let dir_path = args.next().context("'rplace import' expects the path to the directory for permanent storage as the first argument")?;
+            let image_path = args.next().context(
+                "'rplace import' expects the path to the PNG image as the second argument",
+            )?;
+            let mut x = 0;
+            let mut y = 0;
+            let mut blend = false;
+            while let Some(arg) = args.next() {
+                match arg.as_ref() {
+                    "--x" => {
+                        x = args
+                            .next()
+                            .context("'--x' expects the X coordinate of the top left corner")?
+                            .parse()
+                            .context("Invalid X coordinate")?;
+                    }
+                    "--y" => {
+                        y = args
+                            .next()
+                            .context("'--y' expects the Y coordinate of the top left corner")?
+                            .parse()
+                            .context("Invalid Y coordinate")?;
+                    }
+                    "--blend" => blend = true,
+                    _ => bail!("Unknown option {} for 'rplace import'", arg),
+                }
+            }
+            Ok(Command::Import(dir_path, image_path, x, y, blend))
+        }
+        "fsck" => {
+            let dir_path = args.next().context("'rplace fsck' expects the path to the directory for permanent storage as an argument")?;
+            Ok(Command::Fsck(dir_path))
+        }
+        "restore" => {
+            let dir_path = args.next().context("'rplace restore' expects the path to the directory for permanent storage as the first argument")?;
+            let snapshot = args.next().context(
+                "'rplace restore' expects the name or the path of the snapshot as the second argument",
+            )?;
+            Ok(Command::Restore(dir_path, snapshot))
+        }
+        _ => bail!("Unknown CLI command: {}\n\n{}", command, USAGE),
     }
 }
 
 #[rocket::main]
 async fn main() -> Result<()> {
     match get_command()? {
-        Command::Init(dir_path, width, height) => {
+        Command::Init(dir_path, header) => {
             std::fs::create_dir(&dir_path)
                 .context("Failed to create the permanent storage directory")?;
 
-            grid::Grid::create_file(format!("{}/grid", dir_path).as_ref(), width, height)
+            grid::Grid::create_file(format!("{}/grid", dir_path).as_ref(), &header)
                 .context("Failed to create grid data file")?;
 
             println!("Created a storage at {}", dir_path);
             Ok(())
         }
         Command::Serve(dir_path) => {
-            let grid_data_file = std::fs::File::options()
-                .read(true)
-                .write(true)
-                .open(format!("{}/grid", dir_path))
-                .context("Failed to open grid data file")?;
-            let grid =
-                grid::Grid::from_file(&grid_data_file).context("Failed to load grid data file")?;
+            let mut grid = grid::Grid::open(format!("{}/grid", dir_path).as_ref())
+                .context("Failed to load grid data file")?;
 
             let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
                 .context("Failed to load tokendb file")?;
 
+            let mut history = history::History::recover(format!("{}/history", dir_path).as_ref())
+                .context("Failed to load history file")?;
+
+            replay_journal(
+                &mut grid,
+                &mut history,
+                format!("{}/history", dir_path).as_ref(),
+                &tokendb,
+            )?;
+
+            let audit = audit::AuditLog::open(format!("{}/audit.log", dir_path).as_ref())?;
+
             println!("Loaded grid of size {} x {}", grid.width(), grid.height());
 
+            let (grid_updates, grid_updates_rx) = mpsc::unbounded_channel();
+            let (pending_placements, pending_placements_rx) = mpsc::unbounded_channel();
+
+            let config = config::Config::load(dir_path.as_ref())?;
+            let auth_provider = auth::from_config(&config.auth, dir_path.as_ref())?;
+            let oidc = config
+                .oidc
+                .clone()
+                .map(|oidc| Arc::new(oidc::OidcClient::new(oidc)));
+
             let state = Box::leak(Box::new(GlobalState {
+                dir_path,
+                config: std::sync::RwLock::new(Arc::new(config)),
+                auth_provider: std::sync::RwLock::new(auth_provider),
+                oidc: std::sync::RwLock::new(oidc),
                 grid: RwLock::new(grid),
+                history: Mutex::new(history),
                 tokendb,
+                audit: Mutex::new(audit),
                 ws_connections: Arc::new(RwLock::new(HashMap::new())),
+                next_ws_connection_id: AtomicU64::new(0),
+                grid_updates,
+                pending_placements,
             }));
 
-            tokio::spawn(start_ws_server(state));
+            tokio::spawn(run_broadcaster(state, grid_updates_rx));
+            tokio::spawn(run_committer(state, pending_placements_rx));
+            tokio::spawn(run_revealer(state));
+            tokio::spawn(run_snapshotter(state));
+            tokio::spawn(reload_config_on_sighup(state));
             start_http_server(state).await?;
             Ok(())
         }
@@ -379,50 +1773,524 @@ async fn main() -> Result<()> {
             println!("Created token {} for user {}", token, uid);
             Ok(())
         }
+        Command::RevokeToken(dir_path, token) => {
+            let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
+                .context("Failed to load tokendb file")?;
+            let uid = tokendb.revoke_token(tokendb::Token::from_string(&token))?;
+            println!("Revoked token {} of user {}", token, uid);
+            Ok(())
+        }
+        Command::RotateToken(dir_path, uid) => {
+            let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
+                .context("Failed to load tokendb file")?;
+            let config = config::Config::load(dir_path.as_ref())?;
+            let token = tokendb.rotate_token_for_user(&uid, config.token_bytes)?;
+            println!("New token of user {}: {}", uid, token);
+            Ok(())
+        }
+        Command::ListTokens(dir_path) => {
+            let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
+                .context("Failed to load tokendb file")?;
+            for info in tokendb.list_tokens()? {
+                let last_use = match info.last_use {
+                    Some(last_use) => last_use
+                        .duration_since(SystemTime::UNIX_EPOCH)?
+                        .as_millis()
+                        .to_string(),
+                    None => "never".to_string(),
+                };
+                println!("{} {} {}", info.uid, last_use, info.status);
+            }
+            Ok(())
+        }
+        Command::SetUserStatus(dir_path, uid, status) => {
+            let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
+                .context("Failed to load tokendb file")?;
+            tokendb.set_user_status(&uid, status)?;
+            println!("User {} is now {}", uid, status);
+            Ok(())
+        }
+        Command::SetRole(dir_path, uid, role) => {
+            let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
+                .context("Failed to load tokendb file")?;
+            tokendb.set_role(&uid, role)?;
+            println!(
+                "User {} now has role {}",
+                uid,
+                role.map_or("none", |role| role.name())
+            );
+            Ok(())
+        }
         Command::Resize(dir_path, width, height, force) => {
-            let grid_data_file = std::fs::File::options()
+            let grid = grid::Grid::open(format!("{}/grid", dir_path).as_ref())
+                .context("Failed to load grid data file")?;
+
+            let (header, data) = resized_grid(&grid, width, height, force)?;
+            write_resized_grid(&dir_path, &header, &data)?;
+
+            println!("Resized the grid at {}", dir_path);
+            Ok(())
+        }
+        Command::History(dir_path) => {
+            for placement in history::History::read(format!("{}/history", dir_path).as_ref())? {
+                let placement = placement?;
+                let c = placement.color;
+                let p = placement.previous_color;
+                println!(
+                    "{} {} {} {} #{:02x}{:02x}{:02x}{:02x} (was #{:02x}{:02x}{:02x}{:02x})",
+                    placement
+                        .timestamp
+                        .duration_since(SystemTime::UNIX_EPOCH)?
+                        .as_millis(),
+                    placement.uid,
+                    placement.x,
+                    placement.y,
+                    c.r,
+                    c.g,
+                    c.b,
+                    c.a,
+                    p.r,
+                    p.g,
+                    p.b,
+                    p.a
+                );
+            }
+            Ok(())
+        }
+        Command::Timelapse(dir_path, out_dir, step) => {
+            let grid = grid::Grid::open(format!("{}/grid", dir_path).as_ref())
+                .context("Failed to load grid data file")?;
+
+            std::fs::create_dir_all(&out_dir).context("Failed to create the output directory")?;
+
+            let frames = timelapse::render(
+                history::History::read(format!("{}/history", dir_path).as_ref())?,
+                grid.width(),
+                grid.height(),
+                grid.empty_cell(),
+                out_dir.as_ref(),
+                step,
+            )?;
+
+            println!("Rendered {} frames to {}", frames, out_dir);
+            Ok(())
+        }
+        Command::Rollback(dir_path, filter) => {
+            let mut grid = grid::Grid::open(format!("{}/grid", dir_path).as_ref())
+                .context("Failed to load grid data file")?;
+
+            let history_path = format!("{}/history", dir_path);
+            let mut history = history::History::open(history_path.as_ref())
+                .context("Failed to load history file")?;
+
+            let cells = rollback::apply(&mut grid, &mut history, history_path.as_ref(), &filter)?;
+
+            println!("Restored {} cells at {}", cells.len(), dir_path);
+            Ok(())
+        }
+        Command::Export(dir_path, out_path, scale, crop) => {
+            let grid = grid::Grid::open(format!("{}/grid", dir_path).as_ref())
+                .context("Failed to load grid data file")?;
+
+            let crop = match crop {
+                Some([x, y, width, height]) => image::Crop::resolve(
+                    Some(x),
+                    Some(y),
+                    Some(width),
+                    Some(height),
+                    grid.width(),
+                    grid.height(),
+                )?,
+                None => image::Crop::resolve(None, None, None, None, grid.width(), grid.height())?,
+            };
+
+            let file = std::fs::File::create(&out_path).context("Failed to create the PNG file")?;
+            image::export_png(
+                std::io::BufWriter::new(file),
+                &grid.get_data_rgba(),
+                grid.width(),
+                &crop,
+                scale,
+                image::MAX_EXPORT_PIXELS,
+            )?;
+
+            println!("Exported the grid to {}", out_path);
+            Ok(())
+        }
+        Command::Import(dir_path, image_path, offset_x, offset_y, blend) => {
+            let (image_width, image_height, image_data) = image::read_png(image_path.as_ref())?;
+
+            let mut grid = grid::Grid::open(format!("{}/grid", dir_path).as_ref())
+                .context("Failed to load grid data file")?;
+
+            let mut history = history::History::open(format!("{}/history", dir_path).as_ref())
+                .context("Failed to load history file")?;
+
+            let mut changed = 0;
+            for image_y in 0..image_height {
+                for image_x in 0..image_width {
+                    // Pixels that don't fit into the grid are cut off
+                    let x = offset_x + image_x as i64;
+                    let y = offset_y + image_y as i64;
+                    if x < 0 || y < 0 || x >= grid.width() as i64 || y >= grid.height() as i64 {
+                        continue;
+                    }
+                    let (x, y) = (x as usize, y as usize);
+
+                    let offset = (image_y as usize * image_width as usize + image_x as usize) * 4;
+                    let pixel = grid::CellData {
+                        r: image_data[offset],
+                        g: image_data[offset + 1],
+                        b: image_data[offset + 2],
+                        a: image_data[offset + 3],
+                    };
+
+                    let previous_color = grid.get_cell(x, y)?;
+                    let color = if blend {
+                        image::blend(previous_color, pixel)
+                    } else {
+                        pixel
+                    };
+                    // Palette boards can only store palette colors
+                    let color = grid.nearest_color(color);
+                    if color == previous_color {
+                        continue;
+                    }
+
+                    history.append(&history::Placement {
+                        timestamp: SystemTime::now(),
+                        uid: "import".to_string(),
+                        x: x as u32,
+                        y: y as u32,
+                        color,
+                        previous_color,
+                    })?;
+                    grid.write_cell(x, y, color)?;
+                    changed += 1;
+                }
+            }
+            grid.flush()?;
+
+            println!("Imported {} cells into {}", changed, dir_path);
+            Ok(())
+        }
+        Command::Fsck(dir_path) => {
+            let mut problems = 0;
+
+            // Unlike Grid::open, this doesn't upgrade old files
+            let grid = std::fs::File::options()
                 .read(true)
                 .write(true)
                 .open(format!("{}/grid", dir_path))
-                .context("Failed to open grid data file")?;
-            let grid =
-                grid::Grid::from_file(&grid_data_file).context("Failed to load grid data file")?;
-
-            if (width < grid.width() || height < grid.height()) && !force {
-                bail!("The new size is smaller than the current grid size. Add '--force' to cut the bottom right corner");
+                .context("Failed to open grid data file")
+                .and_then(|file| grid::Grid::from_file(&file));
+            match grid {
+                Ok(grid) => {
+                    let header = grid.header();
+                    let format = match header.palette {
+                        Some(ref palette) => format!("palette of {} colors", palette.len()),
+                        None => "RGBA".to_string(),
+                    };
+                    let created_at = match header.created_at {
+                        Some(created_at) => created_at
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .map_or(0, |d| d.as_millis())
+                            .to_string(),
+                        None => "unknown".to_string(),
+                    };
+                    println!(
+                        "grid: version {}, {} x {}, {}, background {}, created at {}, {} placements",
+                        grid.version(),
+                        header.width,
+                        header.height,
+                        format,
+                        header.background.to_hex(),
+                        created_at,
+                        header.placements
+                    );
+                    if grid.version() < grid::VERSION {
+                        println!(
+                            "grid: will be upgraded to version {} on the next start",
+                            grid::VERSION
+                        );
+                    }
+                    let invalid_cells = grid.count_invalid_cells();
+                    if invalid_cells > 0 {
+                        println!(
+                            "grid: {} cells refer to colors outside of the palette",
+                            invalid_cells
+                        );
+                        problems += 1;
+                    }
+                }
+                Err(e) => {
+                    println!("grid: {:#}", e);
+                    problems += 1;
+                }
             }
 
-            let mut data = Vec::with_capacity(height as usize);
-            for y in 0..height {
-                let mut row = Vec::with_capacity(width as usize);
-                for x in 0..width {
-                    row.push(
-                        grid.get_cell(x as usize, y as usize)
-                            .unwrap_or(grid::CellData {
-                                r: 0,
-                                g: 0,
-                                b: 0,
-                                a: 0,
-                            }),
-                    );
+            match tokendb::TokenDB::check(format!("{}/tokendb", dir_path).as_ref()) {
+                Ok(count) => problems += count,
+                Err(e) => {
+                    println!("tokendb: {:#}", e);
+                    problems += 1;
                 }
-                data.push(row);
             }
 
-            grid::Grid::create_file_with_data(
-                format!("{}/grid.tmp", dir_path).as_ref(),
-                width,
-                height,
-                &data,
-            )?;
+            if problems > 0 {
+                bail!("Found {} problems in {}", problems, dir_path);
+            }
+            println!("No problems found in {}", dir_path);
+            Ok(())
+        }
+        Command::Restore(dir_path, snapshot) => {
+            let snapshot_path = snapshot::resolve(&dir_path, &snapshot)?;
 
-            std::fs::rename(
-                format!("{}/grid.tmp", dir_path),
-                format!("{}/grid", dir_path),
-            )?;
+            // This fails while the server is running, which must not have its grid replaced
+            let tokendb = tokendb::TokenDB::open(format!("{}/tokendb", dir_path).as_ref())
+                .context("Failed to load tokendb file")?;
 
-            println!("Resized the grid at {}", dir_path);
+            let tmp_path = format!("{}/grid.tmp", dir_path);
+            let path = format!("{}/grid", dir_path);
+            std::fs::copy(&snapshot_path, &tmp_path).context("Failed to copy snapshot")?;
+            let file = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .open(&tmp_path)
+                .context("Failed to open copied snapshot")?;
+            file.sync_all().context("Failed to copy snapshot")?;
+            let placements = match grid::Grid::from_file(&file) {
+                Ok(grid) => grid.header().placements,
+                Err(e) => {
+                    let _ = std::fs::remove_file(&tmp_path);
+                    return Err(e).context("Invalid snapshot");
+                }
+            };
+
+            // The current grid is kept as a snapshot, so that the restore can be undone
+            match std::fs::read(&path) {
+                Ok(data) => {
+                    let dir = snapshot::dir_path(&dir_path);
+                    let backup = snapshot::write(&dir, SystemTime::now(), &data)?;
+                    println!("Saved the current grid as {}", backup.path.display());
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("Failed to read grid data file"),
+            }
+            std::fs::rename(&tmp_path, &path).context("Failed to replace grid data file")?;
+            snapshot::sync_dir(dir_path.as_ref())?;
+
+            // The journaled placements were made on the replaced grid
+            let dropped = tokendb.clear_journal()?;
+            if dropped > 0 {
+                println!("Dropped {} journaled placements", dropped);
+            }
+
+            println!(
+                "Restored the grid at {} from {} with {} placements",
+                dir_path,
+                snapshot_path.display(),
+                placements
+            );
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: grid::CellData = grid::CellData {
+        r: 255,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    const GREEN: grid::CellData = grid::CellData {
+        r: 0,
+        g: 255,
+        b: 0,
+        a: 255,
+    };
+
+    struct Storage {
+        dir: tempfile::TempDir,
+        grid: grid::Grid,
+        history: history::History,
+        tokendb: tokendb::TokenDB,
+    }
+
+    fn storage() -> Storage {
+        let dir = tempfile::tempdir().unwrap();
+        let grid_path = dir.path().join("grid");
+        grid::Grid::create_file(&grid_path, &grid::Header::new(4, 4, None, None).unwrap()).unwrap();
+        Storage {
+            grid: grid::Grid::open(&grid_path).unwrap(),
+            history: history::History::open(&dir.path().join("history")).unwrap(),
+            tokendb: tokendb::TokenDB::open(&dir.path().join("tokendb")).unwrap(),
+            dir,
+        }
+    }
+
+    // Charges the cooldown of the user, creating a token on first use
+    fn charge(
+        tokendb: &tokendb::TokenDB,
+        uid: &str,
+        cooldown: Duration,
+        x: u32,
+        y: u32,
+        color: grid::CellData,
+    ) -> Result<(tokendb::JournalEntry, tokendb::TokenUse)> {
+        if tokendb.list_tokens()?.iter().all(|token| token.uid != uid) {
+            tokendb.create_token_for_user(uid, 16)?;
+        }
+        let entry = tokendb::JournalEntry {
+            timestamp: SystemTime::now(),
+            uid: String::new(),
+            x,
+            y,
+            color,
+        };
+        let token_use = tokendb.try_use_token_of_user(uid, cooldown, &entry)?;
+        let entry = tokendb::JournalEntry {
+            uid: token_use.uid.clone(),
+            ..entry
+        };
+        Ok((entry, token_use))
+    }
+
+    #[test]
+    fn commits_placements() {
+        let mut storage = storage();
+        let cooldown = Duration::from_secs(60);
+        let (entry, token_use) = charge(&storage.tokendb, "alice", cooldown, 1, 2, RED).unwrap();
+        let history_len = storage.history.len().unwrap();
+
+        let results = write_placements(&mut storage.grid, &mut storage.history, &[entry]);
+        assert!(results[0].is_ok());
+        settle_placements(&storage.tokendb, &[token_use], &results);
+
+        assert!(storage.grid.get_cell(1, 2).unwrap() == RED);
+        assert_eq!(storage.grid.header().placements, 1);
+        assert!(storage.history.len().unwrap() > history_len);
+        assert!(storage.tokendb.journal().unwrap().is_empty());
+        assert!(charge(&storage.tokendb, "alice", cooldown, 1, 2, RED).is_err());
+    }
+
+    #[test]
+    fn refunds_placement_after_failed_history_append() {
+        let mut storage = storage();
+        let cooldown = Duration::from_secs(60);
+        let empty = storage.grid.get_cell(1, 2).unwrap();
+        let (entry, token_use) = charge(&storage.tokendb, "alice", cooldown, 1, 2, RED).unwrap();
+        // UIDs this long don't fit into a history record
+        let entry = tokendb::JournalEntry {
+            uid: "a".repeat(70000),
+            ..entry
+        };
+        let history_len = storage.history.len().unwrap();
+
+        let results = write_placements(&mut storage.grid, &mut storage.history, &[entry]);
+        assert!(results[0].is_err());
+        settle_placements(&storage.tokendb, &[token_use], &results);
+
+        assert!(storage.grid.get_cell(1, 2).unwrap() == empty);
+        assert_eq!(storage.grid.header().placements, 0);
+        assert_eq!(storage.history.len().unwrap(), history_len);
+        assert!(storage.tokendb.journal().unwrap().is_empty());
+        charge(&storage.tokendb, "alice", cooldown, 1, 2, RED).unwrap();
+    }
+
+    #[test]
+    fn refunds_batch_after_failed_sync() {
+        let mut storage = storage();
+        let cooldown = Duration::from_secs(60);
+        let empty = storage.grid.get_cell(1, 2).unwrap();
+        let (first_entry, first_use) =
+            charge(&storage.tokendb, "alice", cooldown, 1, 2, RED).unwrap();
+        let (second_entry, second_use) =
+            charge(&storage.tokendb, "bob", cooldown, 1, 2, GREEN).unwrap();
+        let history_len = storage.history.len().unwrap();
+
+        grid::FAIL_SYNC.set(true);
+        let results = write_placements(
+            &mut storage.grid,
+            &mut storage.history,
+            &[first_entry, second_entry],
+        );
+        grid::FAIL_SYNC.set(false);
+        assert!(results.iter().all(Result::is_err));
+        settle_placements(&storage.tokendb, &[first_use, second_use], &results);
+
+        assert!(storage.grid.get_cell(1, 2).unwrap() == empty);
+        assert_eq!(storage.grid.header().placements, 0);
+        assert_eq!(storage.history.len().unwrap(), history_len);
+        assert!(storage.tokendb.journal().unwrap().is_empty());
+        charge(&storage.tokendb, "alice", cooldown, 1, 2, RED).unwrap();
+        charge(&storage.tokendb, "bob", cooldown, 1, 2, GREEN).unwrap();
+    }
+
+    fn replay(storage: &mut Storage) {
+        replay_journal(
+            &mut storage.grid,
+            &mut storage.history,
+            &storage.dir.path().join("history"),
+            &storage.tokendb,
+        )
+        .unwrap();
+        assert!(storage.tokendb.journal().unwrap().is_empty());
+    }
+
+    fn history_uids(storage: &Storage) -> Vec<String> {
+        history::History::read(&storage.dir.path().join("history"))
+            .unwrap()
+            .map(|placement| placement.unwrap().uid)
+            .collect()
+    }
+
+    #[test]
+    fn replays_placements_missing_from_history() {
+        let mut storage = storage();
+        let cooldown = Duration::ZERO;
+        // The first placement on (0, 0) was written, the others weren't
+        let (entry, _) = charge(&storage.tokendb, "alice", cooldown, 0, 0, RED).unwrap();
+        charge(&storage.tokendb, "bob", cooldown, 0, 0, GREEN).unwrap();
+        charge(&storage.tokendb, "carol", cooldown, 3, 3, RED).unwrap();
+        storage.history.lock().unwrap();
+        apply_placement(&mut storage.grid, &mut storage.history, &entry).unwrap();
+        storage.history.unlock().unwrap();
+
+        replay(&mut storage);
+
+        assert!(storage.grid.get_cell(0, 0).unwrap() == GREEN);
+        assert!(storage.grid.get_cell(3, 3).unwrap() == RED);
+        assert_eq!(storage.grid.header().placements, 3);
+        assert_eq!(history_uids(&storage), ["alice", "bob", "carol"]);
+    }
+
+    #[test]
+    fn restores_cell_from_history_after_replay() {
+        let mut storage = storage();
+        let empty = storage.grid.get_cell(1, 1).unwrap();
+        // The placement was logged, but the crash came before the cell was written
+        let (entry, _) = charge(&storage.tokendb, "alice", Duration::ZERO, 1, 1, RED).unwrap();
+        storage
+            .history
+            .append(&history::Placement {
+                timestamp: entry.timestamp,
+                uid: entry.uid.clone(),
+                x: entry.x,
+                y: entry.y,
+                color: entry.color,
+                previous_color: empty,
+            })
+            .unwrap();
+
+        replay(&mut storage);
+
+        assert!(storage.grid.get_cell(1, 1).unwrap() == RED);
+        assert_eq!(storage.grid.header().placements, 0);
+        assert_eq!(history_uids(&storage), ["alice"]);
+    }
+}
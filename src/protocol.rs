@@ -0,0 +1,230 @@
+use crate::grid::{CellData, Color};
+use anyhow::{bail, Context, Result};
+use tungstenite::protocol::Message;
+
+// Websocket subprotocol names. Clients that don't request a subprotocol get the text protocol
+pub const TEXT_PROTOCOL_NAME: &str = "rplace-text-v1";
+pub const BINARY_PROTOCOL_NAME: &str = "rplace-binary-v1";
+
+// Frame types of the binary protocol
+const FRAME_GRID: u8 = 0x01;
+const FRAME_UPDATES: u8 = 0x02;
+const FRAME_ERROR: u8 = 0x03;
+const FRAME_PALETTE_GRID: u8 = 0x04;
+const FRAME_SET: u8 = 0x10;
+const FRAME_SET_INDEX: u8 = 0x11;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    Text,
+    Binary,
+}
+
+pub struct SetCommand {
+    // None if the client relies on the session cookie sent with the handshake
+    pub token: Option<String>,
+    pub x: usize,
+    pub y: usize,
+    pub color: Color,
+}
+
+impl Protocol {
+    // Picks the first protocol we support from the comma-separated list in the
+    // Sec-WebSocket-Protocol header of the handshake request. Returns None if the client didn't
+    // ask for anything we know, in which case the text protocol is used and no subprotocol is
+    // confirmed
+    pub fn negotiate(requested: Option<&str>) -> Option<Protocol> {
+        requested?.split(',').find_map(|name| match name.trim() {
+            TEXT_PROTOCOL_NAME => Some(Protocol::Text),
+            BINARY_PROTOCOL_NAME => Some(Protocol::Binary),
+            _ => None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Text => TEXT_PROTOCOL_NAME,
+            Protocol::Binary => BINARY_PROTOCOL_NAME,
+        }
+    }
+
+    // The data is as stored in the grid, i.e. palette indices if there is a palette
+    pub fn encode_grid(
+        &self,
+        width: u32,
+        height: u32,
+        palette: Option<&[CellData]>,
+        data: Vec<u8>,
+    ) -> Vec<Message> {
+        match (self, palette) {
+            (Protocol::Text, None) => vec![
+                Message::Text(format!("grid {} {}", width, height)),
+                Message::Binary(data),
+            ],
+            (Protocol::Text, Some(palette)) => {
+                let colors: Vec<String> = palette.iter().map(|color| color.to_hex()).collect();
+                vec![
+                    Message::Text(format!("palette {}", colors.join(" "))),
+                    Message::Text(format!("grid {} {}", width, height)),
+                    Message::Binary(data),
+                ]
+            }
+            (Protocol::Binary, None) => {
+                let mut frame = Vec::with_capacity(9 + data.len());
+                frame.push(FRAME_GRID);
+                frame.extend_from_slice(&width.to_le_bytes());
+                frame.extend_from_slice(&height.to_le_bytes());
+                frame.extend_from_slice(&data);
+                vec![Message::Binary(frame)]
+            }
+            (Protocol::Binary, Some(palette)) => {
+                let mut frame = Vec::with_capacity(13 + 4 * palette.len() + data.len());
+                frame.push(FRAME_PALETTE_GRID);
+                frame.extend_from_slice(&width.to_le_bytes());
+                frame.extend_from_slice(&height.to_le_bytes());
+                frame.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+                for color in palette {
+                    frame.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+                }
+                frame.extend_from_slice(&data);
+                vec![Message::Binary(frame)]
+            }
+        }
+    }
+
+    pub fn encode_updates(&self, updates: &[(u32, u32, CellData)]) -> Vec<Message> {
+        match self {
+            Protocol::Text => updates
+                .iter()
+                .map(|&(x, y, cell)| {
+                    Message::Text(format!(
+                        "set {} {} {} {} {} {}",
+                        x, y, cell.r, cell.g, cell.b, cell.a
+                    ))
+                })
+                .collect(),
+            Protocol::Binary => {
+                let mut frame = Vec::with_capacity(5 + 12 * updates.len());
+                frame.push(FRAME_UPDATES);
+                frame.extend_from_slice(&(updates.len() as u32).to_le_bytes());
+                for &(x, y, cell) in updates {
+                    frame.extend_from_slice(&x.to_le_bytes());
+                    frame.extend_from_slice(&y.to_le_bytes());
+                    frame.extend_from_slice(&[cell.r, cell.g, cell.b, cell.a]);
+                }
+                vec![Message::Binary(frame)]
+            }
+        }
+    }
+
+    pub fn encode_error(&self, error: &anyhow::Error) -> Message {
+        match self {
+            Protocol::Text => Message::Text(format!("error {}", error)),
+            Protocol::Binary => {
+                let text = error.to_string();
+                let mut frame = Vec::with_capacity(1 + text.len());
+                frame.push(FRAME_ERROR);
+                frame.extend_from_slice(text.as_bytes());
+                Message::Binary(frame)
+            }
+        }
+    }
+
+    pub fn decode_command(&self, msg: &Message) -> Result<SetCommand> {
+        match (self, msg) {
+            (Protocol::Text, Message::Text(s)) => decode_text_command(s),
+            (Protocol::Text, _) => bail!("Invalid message: must be text"),
+            (Protocol::Binary, Message::Binary(frame)) => decode_binary_command(frame),
+            (Protocol::Binary, _) => bail!("Invalid message: must be binary"),
+        }
+    }
+}
+
+// The color is either four RGBA components or a single palette index, so the number of arguments
+// tells whether the token is present
+fn decode_text_command(s: &str) -> Result<SetCommand> {
+    let parts: Vec<&str> = s.split(" ").collect();
+    let (has_token, color_len) = match parts.len() {
+        7 => (false, 4),
+        8 => (true, 4),
+        4 => (false, 1),
+        5 => (true, 1),
+        _ => (false, 0),
+    };
+    if color_len == 0 || parts[0] != "set" {
+        bail!("Invalid command syntax: must be 'set [<token>] <x> <y> <r> <g> <b> <a>' or 'set [<token>] <x> <y> <palette_index>'");
+    }
+    let token = if has_token {
+        Some(parts[1].to_string())
+    } else {
+        None
+    };
+    let nums_start = parts.len() - 2 - color_len;
+
+    let mut nums = [0usize; 6];
+    for i in 0..2 + color_len {
+        match parts[nums_start + i].parse() {
+            Ok(num) => nums[i] = num,
+            Err(e) => {
+                bail!("Invalid command syntax: not a number: {}", e);
+            }
+        }
+    }
+    if nums[2..2 + color_len].iter().max().unwrap() > &255 {
+        bail!("Invalid command syntax: color components and palette indices must be in range 0..255 (inclusive)");
+    }
+
+    let color = if color_len == 1 {
+        Color::Index(nums[2] as u8)
+    } else {
+        Color::Rgba(CellData {
+            r: nums[2] as u8,
+            g: nums[3] as u8,
+            b: nums[4] as u8,
+            a: nums[5] as u8,
+        })
+    };
+
+    Ok(SetCommand {
+        token,
+        x: nums[0],
+        y: nums[1],
+        color,
+    })
+}
+
+fn decode_binary_command(frame: &[u8]) -> Result<SetCommand> {
+    if frame.len() < 2 || !(frame[0] == FRAME_SET || frame[0] == FRAME_SET_INDEX) {
+        bail!("Invalid frame: must be a set frame");
+    }
+    let token_len = frame[1] as usize;
+    let color_len = if frame[0] == FRAME_SET { 4 } else { 1 };
+    if frame.len() != 2 + token_len + 8 + color_len {
+        bail!(
+            "Invalid frame: set frame must be {} bytes long plus the token length",
+            10 + color_len
+        );
+    }
+    let token = std::str::from_utf8(&frame[2..2 + token_len])
+        .context("Invalid frame: token must be UTF-8")?;
+    let rest = &frame[2 + token_len..];
+    Ok(SetCommand {
+        token: if token.is_empty() {
+            None
+        } else {
+            Some(token.to_string())
+        },
+        x: u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize,
+        y: u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize,
+        color: if frame[0] == FRAME_SET {
+            Color::Rgba(CellData {
+                r: rest[8],
+                g: rest[9],
+                b: rest[10],
+                a: rest[11],
+            })
+        } else {
+            Color::Index(rest[8])
+        },
+    })
+}
@@ -0,0 +1,153 @@
+use crate::config::{RegionConfig, RegionState};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+impl RegionConfig {
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x
+            && y >= self.y
+            && ((x - self.x) as u64) < self.width as u64
+            && ((y - self.y) as u64) < self.height as u64
+    }
+
+    // None if there is no reveal time or it doesn't fit into a SystemTime, which Config::load
+    // rejects
+    pub fn reveal_time(&self) -> Option<SystemTime> {
+        self.reveal_at
+            .and_then(|secs| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs)))
+    }
+
+    // Hidden regions become open once their reveal time has come
+    pub fn state(&self, now: SystemTime) -> RegionState {
+        match self.reveal_time() {
+            Some(reveal_time) if self.state == RegionState::Hidden && reveal_time <= now => {
+                RegionState::Open
+            }
+            _ => self.state,
+        }
+    }
+}
+
+// The last region that contains the cell decides its state. Cells outside of all regions are open
+pub fn state_at(regions: &[RegionConfig], x: u32, y: u32, now: SystemTime) -> RegionState {
+    regions
+        .iter()
+        .rev()
+        .find(|region| region.contains(x, y))
+        .map_or(RegionState::Open, |region| region.state(now))
+}
+
+pub fn check_writable(regions: &[RegionConfig], x: u32, y: u32, now: SystemTime) -> Result<()> {
+    match state_at(regions, x, y, now) {
+        RegionState::Open => Ok(()),
+        RegionState::Locked => bail!("Cell ({}, {}) is in a locked region", x, y),
+        RegionState::Hidden => bail!("Cell ({}, {}) is in a region that is not open yet", x, y),
+    }
+}
+
+// Cells of a width x height grid that are hidden under one of the two region lists but not under
+// the other, together with whether they are hidden under the new one
+pub fn visibility_changes(
+    old: &[RegionConfig],
+    new: &[RegionConfig],
+    now: SystemTime,
+    width: u32,
+    height: u32,
+) -> Vec<(u32, u32, bool)> {
+    let mut changes = Vec::new();
+    let mut checked = HashSet::new();
+    for region in old.iter().chain(new) {
+        for y in region.y..region.y.saturating_add(region.height).min(height) {
+            for x in region.x..region.x.saturating_add(region.width).min(width) {
+                if !checked.insert((x, y)) {
+                    continue;
+                }
+                let was_hidden = state_at(old, x, y, now) == RegionState::Hidden;
+                let is_hidden = state_at(new, x, y, now) == RegionState::Hidden;
+                if was_hidden != is_hidden {
+                    changes.push((x, y, is_hidden));
+                }
+            }
+        }
+    }
+    changes
+}
+
+// Replaces the hidden cells in serialized grid data with the empty cell, so that clients can't see
+// what's prepared there
+pub fn mask_hidden(
+    regions: &[RegionConfig],
+    now: SystemTime,
+    width: u32,
+    height: u32,
+    empty_cell: &[u8],
+    data: &mut [u8],
+) {
+    let cell_size = empty_cell.len();
+    for region in regions {
+        if region.state(now) != RegionState::Hidden {
+            continue;
+        }
+        for y in region.y..region.y.saturating_add(region.height).min(height) {
+            for x in region.x..region.x.saturating_add(region.width).min(width) {
+                if state_at(regions, x, y, now) == RegionState::Hidden {
+                    let offset = (y as usize * width as usize + x as usize) * cell_size;
+                    data[offset..offset + cell_size].copy_from_slice(empty_cell);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: u32, y: u32, state: RegionState, reveal_at: Option<u64>) -> RegionConfig {
+        RegionConfig {
+            x,
+            y,
+            width: 2,
+            height: 2,
+            state,
+            reveal_at,
+        }
+    }
+
+    #[test]
+    fn visibility_changes_between_configurations() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let old = [
+            region(0, 0, RegionState::Hidden, None),
+            region(4, 0, RegionState::Locked, None),
+        ];
+        let new = [
+            // Revealed by moving the reveal time into the past
+            region(0, 0, RegionState::Hidden, Some(500)),
+            // Hidden, but cut off by the edge of the grid
+            region(4, 0, RegionState::Hidden, None),
+        ];
+
+        let mut changes = visibility_changes(&old, &new, now, 5, 5);
+        changes.sort();
+        assert_eq!(
+            changes,
+            [
+                (0, 0, false),
+                (0, 1, false),
+                (1, 0, false),
+                (1, 1, false),
+                (4, 0, true),
+                (4, 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_visibility_changes_for_same_configuration() {
+        let now = SystemTime::UNIX_EPOCH;
+        let regions = [region(1, 1, RegionState::Hidden, Some(10))];
+        assert!(visibility_changes(&regions, &regions, now, 5, 5).is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::grid::{CellData, Grid};
+use crate::history::{History, Placement};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+pub const ROLLBACK_UID: &str = "rollback";
+
+pub struct Filter {
+    pub uid: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.since.is_none() && self.until.is_none()
+    }
+
+    fn matches(&self, placement: &Placement) -> bool {
+        if let Some(ref uid) = self.uid {
+            if placement.uid != *uid {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if placement.timestamp < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if placement.timestamp >= until {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Finds the cells whose latest placements match the filter and the colors they had before that
+// run of matching placements. Cells that somebody else has painted over since are left alone.
+pub fn plan(
+    placements: impl Iterator<Item = Result<Placement>>,
+    filter: &Filter,
+) -> Result<Vec<(u32, u32, CellData)>> {
+    let mut restore: HashMap<(u32, u32), Option<CellData>> = HashMap::new();
+    for placement in placements {
+        let placement = placement?;
+        let entry = restore.entry((placement.x, placement.y)).or_insert(None);
+        if filter.matches(&placement) {
+            if entry.is_none() {
+                *entry = Some(placement.previous_color);
+            }
+        } else {
+            *entry = None;
+        }
+    }
+
+    let mut cells: Vec<(u32, u32, CellData)> = restore
+        .into_iter()
+        .filter_map(|((x, y), color)| color.map(|color| (x, y, color)))
+        .collect();
+    cells.sort_by_key(|&(x, y, _)| (y, x));
+    Ok(cells)
+}
+
+// Applies a rollback to the grid and records the restored cells in the history. Returns the
+// cells that were changed
+pub fn apply(
+    grid: &mut Grid,
+    history: &mut History,
+    history_path: &Path,
+    filter: &Filter,
+) -> Result<Vec<(u32, u32, CellData)>> {
+    let mut cells = plan(History::read(history_path)?, filter)?;
+
+    // Cells that were cut off by a resize can't be restored
+    cells.retain(|&(x, y, _)| x < grid.width() && y < grid.height());
+
+    for &(x, y, color) in &cells {
+        let previous_color = grid.get_cell(x as usize, y as usize)?;
+        history.append(&Placement {
+            timestamp: SystemTime::now(),
+            uid: ROLLBACK_UID.to_string(),
+            x,
+            y,
+            color,
+            previous_color,
+        })?;
+        grid.set_cell(x as usize, y as usize, color)?;
+    }
+
+    Ok(cells)
+}
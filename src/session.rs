@@ -0,0 +1,93 @@
+use anyhow::{bail, Result};
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::time::OffsetDateTime;
+use std::time::{Duration, SystemTime};
+
+const COOKIE_NAME: &str = "rplace_session";
+
+// Request guard for the session cookie set on login. The cookie is private, i.e. encrypted and
+// signed with Rocket's secret key, so it can hold the UID directly: "<uid> <expiry timestamp>"
+pub struct Session {
+    uid: String,
+    expires_at: SystemTime,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let cookies = request.cookies();
+        let cookie = match cookies.get_private(COOKIE_NAME) {
+            Some(cookie) => cookie,
+            None => return Outcome::Forward(rocket::http::Status::Unauthorized),
+        };
+        match Session::parse(cookie.value()) {
+            Some(session) if !session.is_expired() => Outcome::Success(session),
+            _ => {
+                // The browser should have dropped it by now, but expiry can't be trusted to the
+                // client
+                end(cookies);
+                Outcome::Forward(rocket::http::Status::Unauthorized)
+            }
+        }
+    }
+}
+
+impl Session {
+    fn parse(value: &str) -> Option<Session> {
+        let (uid, expires_at) = value.rsplit_once(' ')?;
+        let expires_at = SystemTime::UNIX_EPOCH + Duration::from_secs(expires_at.parse().ok()?);
+        Some(Session {
+            uid: uid.to_string(),
+            expires_at,
+        })
+    }
+
+    fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires_at
+    }
+
+    // Websocket connections may outlive the session, so the expiry is checked on each use
+    pub fn uid(&self) -> Result<&str> {
+        if self.is_expired() {
+            bail!("Your session has expired, please log in again");
+        }
+        Ok(&self.uid)
+    }
+}
+
+// When a session started now ends, or None if that is too far in the future for the clock or for
+// a cookie
+pub fn expiry(lifetime: Duration) -> Option<SystemTime> {
+    let expires_at = SystemTime::now().checked_add(lifetime)?;
+    let secs = expires_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    OffsetDateTime::from_unix_timestamp(secs.try_into().ok()?).ok()?;
+    Some(expires_at)
+}
+
+pub fn start(cookies: &CookieJar<'_>, uid: &str, lifetime: Duration) -> Result<()> {
+    let expires_at = match expiry(lifetime) {
+        Some(expires_at) => expires_at,
+        None => bail!("Session lifetime is too long"),
+    };
+    let expires_at_secs = expires_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let mut cookie = Cookie::new(COOKIE_NAME, format!("{} {}", uid, expires_at_secs));
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_path("/");
+    cookie.set_expires(OffsetDateTime::from(expires_at));
+    cookies.add_private(cookie);
+    Ok(())
+}
+
+pub fn end(cookies: &CookieJar<'_>) {
+    cookies.remove_private(Cookie::build(COOKIE_NAME).path("/"));
+}
@@ -0,0 +1,208 @@
+use crate::config::SnapshotConfig;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// Snapshots are copies of the grid data file named "grid-<unix time in seconds>"
+const PREFIX: &str = "grid-";
+
+pub const HOUR: u64 = 60 * 60;
+pub const DAY: u64 = 24 * HOUR;
+
+pub struct Snapshot {
+    pub time: SystemTime,
+    pub path: PathBuf,
+}
+
+pub fn dir_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join("snapshots")
+}
+
+// Writes the contents of the grid data file into a new snapshot. The file is written under a
+// temporary name first, so that a crash doesn't leave a partial snapshot behind
+pub fn write(dir: &Path, time: SystemTime, data: &[u8]) -> Result<Snapshot> {
+    std::fs::create_dir_all(dir).context("Failed to create snapshot directory")?;
+    let name = format!("{}{}", PREFIX, unix_secs(time));
+    let path = dir.join(&name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+
+    let mut file = File::create(&tmp_path).context("Failed to create snapshot")?;
+    file.write_all(data).context("Failed to write snapshot")?;
+    file.sync_all().context("Failed to write snapshot")?;
+    std::fs::rename(&tmp_path, &path).context("Failed to move snapshot into place")?;
+    sync_dir(dir)?;
+
+    Ok(Snapshot { time, path })
+}
+
+// Waits until renames in the directory are on disk
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync directory {}", dir.display()))
+}
+
+// Returns the snapshots in the directory, oldest first. Other files are ignored
+pub fn list(dir: &Path) -> Result<Vec<Snapshot>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read snapshot directory"),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let entry = entry.context("Failed to read snapshot directory")?;
+        let time = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(PREFIX))
+            .and_then(|secs| secs.parse().ok())
+            .and_then(|secs| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs)));
+        if let Some(time) = time {
+            snapshots.push(Snapshot {
+                time,
+                path: entry.path(),
+            });
+        }
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.time);
+    Ok(snapshots)
+}
+
+// Deletes the snapshots that the retention policy doesn't keep: the first snapshot of each hour
+// for keep_hourly_hours, then the first one of each day for keep_daily_days. The latest snapshot
+// is always kept. Returns the number of deleted snapshots
+pub fn prune(dir: &Path, config: &SnapshotConfig, now: SystemTime) -> Result<usize> {
+    let snapshots = list(dir)?;
+    let mut kept_periods = HashSet::new();
+    let mut deleted = 0;
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        let age = now.duration_since(snapshot.time).unwrap_or(Duration::ZERO);
+        let period_len = if age < config.keep_hourly() {
+            HOUR
+        } else if config.keep_daily().is_none_or(|keep| age < keep) {
+            DAY
+        } else {
+            0
+        };
+
+        let is_latest = i + 1 == snapshots.len();
+        if is_latest
+            || (period_len > 0
+                && kept_periods.insert((period_len, unix_secs(snapshot.time) / period_len)))
+        {
+            continue;
+        }
+        std::fs::remove_file(&snapshot.path)
+            .with_context(|| format!("Failed to delete snapshot {}", snapshot.path.display()))?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+// Finds a snapshot by its file name in the snapshot directory, or by its path
+pub fn resolve(data_dir: &str, snapshot: &str) -> Result<PathBuf> {
+    let dir = dir_path(data_dir);
+    if dir.join(snapshot).is_file() {
+        return Ok(dir.join(snapshot));
+    }
+    if Path::new(snapshot).is_file() {
+        return Ok(PathBuf::from(snapshot));
+    }
+    bail!(
+        "Snapshot {} not found, neither in {} nor as a path",
+        snapshot,
+        dir.display()
+    )
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60;
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn prune_keeps_first_snapshot_of_each_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let config = SnapshotConfig {
+            interval_minutes: 10,
+            keep_hourly_hours: 2,
+            keep_daily_days: Some(3),
+        };
+        let ages = [
+            5 * DAY,
+            DAY + 2 * HOUR,
+            DAY + HOUR,
+            100 * MINUTE,
+            90 * MINUTE,
+            30 * MINUTE,
+            20 * MINUTE,
+            MINUTE,
+        ];
+        for age in ages {
+            write(dir.path(), now - Duration::from_secs(age), b"grid").unwrap();
+        }
+        // Not snapshots, and not even valid times
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        std::fs::write(dir.path().join("grid-18446744073709551615"), "").unwrap();
+
+        assert_eq!(prune(dir.path(), &config, now).unwrap(), 4);
+        let kept = [DAY + 2 * HOUR, 100 * MINUTE, 30 * MINUTE, MINUTE];
+        let mut expected: Vec<String> = kept
+            .iter()
+            .map(|age| format!("grid-{}", 1000 * DAY - age))
+            .collect();
+        expected.push("grid-18446744073709551615".to_string());
+        expected.push("notes.txt".to_string());
+        expected.sort();
+        assert_eq!(names(dir.path()), expected);
+    }
+
+    #[test]
+    fn prune_keeps_latest_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let config = SnapshotConfig {
+            interval_minutes: 10,
+            keep_hourly_hours: 1,
+            keep_daily_days: Some(1),
+        };
+        let time = now - Duration::from_secs(10 * DAY);
+        write(dir.path(), time - Duration::from_secs(DAY), b"grid").unwrap();
+        write(dir.path(), time, b"grid").unwrap();
+
+        assert_eq!(prune(dir.path(), &config, now).unwrap(), 1);
+        assert_eq!(names(dir.path()), [format!("grid-{}", 990 * DAY)]);
+    }
+
+    #[test]
+    fn prune_of_missing_directory_does_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SnapshotConfig::default();
+        assert_eq!(
+            prune(&dir.path().join("snapshots"), &config, SystemTime::now()).unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::grid::{CellData, Grid};
+use crate::history::Placement;
+use crate::image;
+use anyhow::Result;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+pub enum Step {
+    Placements(u64),
+    Interval(Duration),
+}
+
+struct FrameWriter<'a> {
+    out_dir: &'a Path,
+    count: usize,
+}
+
+impl FrameWriter<'_> {
+    fn write(&mut self, grid: &Grid) -> Result<()> {
+        image::write_png(
+            &self.out_dir.join(format!("frame_{:06}.png", self.count)),
+            grid.width(),
+            grid.height(),
+            &grid.get_data_serialized(),
+        )?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+pub fn render(
+    placements: impl Iterator<Item = Result<Placement>>,
+    width: u32,
+    height: u32,
+    background: CellData,
+    out_dir: &Path,
+    step: Step,
+) -> Result<usize> {
+    // Frames are rendered in memory, so cells are never flushed
+    let mut grid = Grid::new_anonymous(width, height, background)?;
+    let mut frames = FrameWriter { out_dir, count: 0 };
+
+    let mut placements_in_frame = 0;
+    let mut next_frame_at: Option<SystemTime> = None;
+
+    for placement in placements {
+        let placement = placement?;
+
+        match step {
+            Step::Placements(_) => {}
+            Step::Interval(interval) => {
+                let next = next_frame_at.get_or_insert(placement.timestamp + interval);
+                // Emit a frame for every interval that has passed, even if nothing happened
+                // during it, so that the timelapse runs at a constant speed
+                while placement.timestamp >= *next {
+                    frames.write(&grid)?;
+                    *next += interval;
+                }
+            }
+        }
+
+        // Placements made before the grid was shrunk may be out of bounds, skip them
+        if grid
+            .write_cell(placement.x as usize, placement.y as usize, placement.color)
+            .is_err()
+        {
+            continue;
+        }
+
+        if let Step::Placements(per_frame) = step {
+            placements_in_frame += 1;
+            if placements_in_frame == per_frame {
+                frames.write(&grid)?;
+                placements_in_frame = 0;
+            }
+        }
+    }
+
+    if placements_in_frame > 0 || next_frame_at.is_some() || frames.count == 0 {
+        frames.write(&grid)?;
+    }
+
+    Ok(frames.count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_start_from_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let background = CellData {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        };
+        let color = CellData {
+            r: 1,
+            g: 2,
+            b: 3,
+            a: 255,
+        };
+        let placements = [(0, 0), (5, 5)].map(|(x, y)| {
+            Ok(Placement {
+                timestamp: SystemTime::now(),
+                uid: "alice".to_string(),
+                x,
+                y,
+                color,
+                previous_color: background,
+            })
+        });
+
+        let frames = render(
+            placements.into_iter(),
+            2,
+            1,
+            background,
+            dir.path(),
+            Step::Placements(1),
+        )
+        .unwrap();
+
+        assert_eq!(frames, 1);
+        let (width, height, data) = image::read_png(&dir.path().join("frame_000000.png")).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(data, [1, 2, 3, 255, 255, 255, 255, 255]);
+    }
+}
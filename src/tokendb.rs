@@ -1,13 +1,39 @@
+use crate::grid::CellData;
 use anyhow::{anyhow, bail, Context, Error, Result};
-use rand::Fill;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use sled::transaction::{ConflictableTransactionError, TransactionError};
-use std::fmt::Write as FmtWrite;
+use std::collections::HashMap;
+use std::fmt::{self, Write as FmtWrite};
 use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+
+// Version 1 stored raw tokens as keys, prefixed with 0xff. Version 2 stores an HMAC of the token
+// instead, prefixed with 0xfe, so that the database alone is not enough to impersonate users
+const VERSION: u32 = 2;
+const VERSION_KEY: &[u8] = b"meta/version";
+const RAW_TOKEN_PREFIX: u8 = 0xff;
+const HASHED_TOKEN_PREFIX: u8 = 0xfe;
+
+// Placements that were charged for but may not have reached the grid yet are kept under this
+// prefix, followed by a big-endian sequence number
+const JOURNAL_PREFIX: &[u8] = b"journal/";
+
+// Issued tokens look like "rpl_<random bytes in hex><checksum>", where the checksum is the first
+// 4 bytes of the SHA-256 of everything before it, in hex. Tokens issued by older versions and
+// tokens added by hand don't have the prefix and are not checked
+const TOKEN_PREFIX: &str = "rpl_";
+const CHECKSUM_LEN: usize = 8;
 
 pub struct TokenDB {
     db: sled::Db,
+    // HMAC key for tokens. It is kept in a separate file next to the database
+    key: Vec<u8>,
 }
 
 pub struct Token(String);
@@ -17,6 +43,50 @@ struct TokenData {
     last_use: SystemTime,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum UserStatus {
+    Active,
+    // Placements are rejected until the given time, or forever if it is None
+    Banned(Option<SystemTime>),
+    // Placements seem to succeed, but are only shown to the user themselves
+    Shadowbanned,
+}
+
+// Access to the admin API. Admins can do everything moderators can
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Moderator,
+    Admin,
+}
+
+// Result of a successful try_use_token
+pub struct TokenUse {
+    pub uid: String,
+    // ID of the journaled placement, or None if the user is shadowbanned and the placement must
+    // not reach the grid
+    pub journal_id: Option<u64>,
+    // What refund needs to undo the charge
+    key: Vec<u8>,
+    previous_last_use: SystemTime,
+}
+
+// A placement that is journaled in the same transaction that charges the cooldown, so that the
+// cooldown is never charged for a placement that gets lost in a crash
+pub struct JournalEntry {
+    pub timestamp: SystemTime,
+    pub uid: String,
+    pub x: u32,
+    pub y: u32,
+    pub color: CellData,
+}
+
+pub struct TokenInfo {
+    pub uid: String,
+    pub last_use: Option<SystemTime>,
+    pub status: UserStatus,
+    pub role: Option<Role>,
+}
+
 fn to_abort(
     e: anyhow::Error,
 ) -> ConflictableTransactionError<Box<dyn std::error::Error + Send + Sync + 'static>> {
@@ -33,27 +103,191 @@ fn from_abort(
 }
 
 impl TokenDB {
+    // Opens the database at `path` with the key at `<path>.key`, creating the key if necessary,
+    // and upgrades the database to the current version
     pub fn open(path: &Path) -> Result<TokenDB> {
-        Ok(TokenDB {
-            db: sled::open(path).context("Failed to open token database")?,
-        })
+        let migrating_path = path.with_extension("migrating");
+        let migrated_path = path.with_extension("migrated");
+
+        // Finish or discard a migration that was interrupted by a crash
+        if migrated_path.exists() {
+            if path.exists() {
+                std::fs::remove_dir_all(path).context("Failed to remove old token database")?;
+            }
+            std::fs::rename(&migrated_path, path).context("Failed to move token database")?;
+        }
+        if migrating_path.exists() {
+            std::fs::remove_dir_all(&migrating_path)
+                .context("Failed to remove incomplete token database")?;
+        }
+
+        let db = sled::open(path).context("Failed to open token database")?;
+        let version = match db.get(VERSION_KEY)? {
+            Some(version) => u32::from_le_bytes(
+                version
+                    .as_ref()
+                    .try_into()
+                    .context("Invalid token database version")?,
+            ),
+            None if db.scan_prefix([RAW_TOKEN_PREFIX]).next().is_some() => 1,
+            None => VERSION,
+        };
+
+        // A new key would silently invalidate all hashed tokens, so it is only generated for
+        // databases that don't have any yet
+        let may_create_key = db.get(VERSION_KEY)?.is_none();
+        let key = load_key(&path.with_extension("key"), may_create_key)?;
+
+        match version {
+            1 => {
+                // sled keeps overwritten data in its log for a while, so the raw tokens are
+                // copied into a new database and the old one is deleted altogether
+                let count = migrate_from_v1(&db, &migrating_path, &key)?;
+                drop(db);
+                std::fs::rename(&migrating_path, &migrated_path)
+                    .context("Failed to move token database")?;
+                std::fs::remove_dir_all(path).context("Failed to remove old token database")?;
+                std::fs::rename(&migrated_path, path).context("Failed to move token database")?;
+                println!("Migrated {} tokens to hashed storage", count);
+
+                Ok(TokenDB {
+                    db: sled::open(path).context("Failed to open token database")?,
+                    key,
+                })
+            }
+            VERSION => {
+                db.insert(VERSION_KEY, &VERSION.to_le_bytes())?;
+                Ok(TokenDB { db, key })
+            }
+            _ => bail!("Token database is of unknown version {}", version),
+        }
+    }
+
+    // Checks the database at `path` for consistency without modifying it, printing each problem.
+    // Returns the number of problems
+    pub fn check(path: &Path) -> Result<usize> {
+        // Storages created with 'rplace init' don't have one until the first token is added
+        if !path.exists() {
+            println!("tokendb: not created yet");
+            return Ok(0);
+        }
+        let db = sled::open(path).context("Failed to open token database")?;
+        let mut problems = 0;
+        let mut problem = |message: String| {
+            println!("tokendb: {}", message);
+            problems += 1;
+        };
+
+        let version = match db.get(VERSION_KEY)? {
+            Some(version) => match version.as_ref().try_into() {
+                Ok(version) => u32::from_le_bytes(version),
+                Err(_) => bail!("Invalid token database version"),
+            },
+            None if db.scan_prefix([RAW_TOKEN_PREFIX]).next().is_some() => 1,
+            None => VERSION,
+        };
+        match version {
+            1 => {
+                println!("tokendb: version 1, will be migrated to hashed storage on the next start")
+            }
+            // The key is only created once the server first opens the database
+            VERSION if db.contains_key(VERSION_KEY)? => {
+                if let Err(e) = load_key(&path.with_extension("key"), false) {
+                    problem(format!("{:#}", e));
+                }
+            }
+            VERSION => {}
+            _ => bail!("Token database is of unknown version {}", version),
+        }
+
+        let mut tokens = HashMap::new();
+        let mut index = Vec::new();
+        let mut journaled = 0;
+        for entry in db.iter() {
+            let (key, value) = entry?;
+            if key.first() == Some(&HASHED_TOKEN_PREFIX) || key.first() == Some(&RAW_TOKEN_PREFIX) {
+                if key[0] == RAW_TOKEN_PREFIX && version == VERSION {
+                    problem("A raw token is left over from version 1".to_string());
+                } else if key[0] == HASHED_TOKEN_PREFIX && key.len() != 33 {
+                    problem(format!("A hashed token key is {} bytes long", key.len()));
+                }
+                match TokenData::try_from_buf(value.as_ref()) {
+                    Ok(data) => {
+                        tokens.insert(key.to_vec(), data.uid);
+                    }
+                    Err(e) => problem(format!("Invalid token data: {}", e)),
+                }
+            } else if let Some(uid) = key.strip_prefix(b"token_by_uid/") {
+                index.push((String::from_utf8_lossy(uid).into_owned(), value.to_vec()));
+            } else if let Some(uid) = key.strip_prefix(b"user_status/") {
+                if let Err(e) = UserStatus::try_from_buf(value.as_ref()) {
+                    let uid = String::from_utf8_lossy(uid);
+                    problem(format!("Invalid status of user {:?}: {}", uid, e));
+                }
+            } else if let Some(uid) = key.strip_prefix(b"role/") {
+                let role = std::str::from_utf8(value.as_ref())
+                    .map_err(Error::from)
+                    .and_then(Role::from_name);
+                if let Err(e) = role {
+                    let uid = String::from_utf8_lossy(uid);
+                    problem(format!("Invalid role of user {:?}: {}", uid, e));
+                }
+            } else if key.starts_with(JOURNAL_PREFIX) {
+                let entry = journal_id_from_key(&key)
+                    .and_then(|_| JournalEntry::try_from_buf(value.as_ref()));
+                match entry {
+                    Ok(_) => journaled += 1,
+                    Err(e) => problem(format!("Invalid journal entry: {}", e)),
+                }
+            } else if key.as_ref() != VERSION_KEY {
+                problem(format!("Unknown key {:?}", String::from_utf8_lossy(&key)));
+            }
+        }
+
+        // Tokens added by hand may not be indexed, but every index entry must lead to a token
+        for (uid, key) in index {
+            match tokens.get(&key) {
+                Some(owner) if *owner == uid => {}
+                Some(owner) => problem(format!(
+                    "The token of user {:?} belongs to user {:?}",
+                    uid, owner
+                )),
+                None => problem(format!("The token of user {:?} does not exist", uid)),
+            }
+        }
+
+        println!("tokendb: version {}, {} tokens", version, tokens.len());
+        if journaled > 0 {
+            println!(
+                "tokendb: {} placements are pending in the journal and will be replayed on the next start",
+                journaled
+            );
+        }
+        Ok(problems)
+    }
+
+    fn key_of(&self, token: &Token) -> Vec<u8> {
+        hash_token(&self.key, &token.0)
     }
 
     pub fn add_token(&self, token: Token, uid: &str) -> Result<Token> {
+        token.check()?;
+        let key = self.key_of(&token);
         self.db
             .transaction(|tx_db: &sled::transaction::TransactionalTree| {
-                if let Some(old_token) = tx_db.get(format!("token_by_uid/{}", uid).as_bytes())? {
-                    let old_token = Token::try_from_bytes(old_token.as_ref()).map_err(to_abort)?;
+                if tx_db
+                    .get(format!("token_by_uid/{}", uid).as_bytes())?
+                    .is_some()
+                {
                     return Err(to_abort(anyhow!(
-                        "You already have a token: {}",
-                        old_token.to_string()
+                        "You already have a token. If you lost it, ask an administrator to issue a new one"
                     )));
                 }
 
-                tx_db.insert(format!("token_by_uid/{}", uid).as_bytes(), token.to_bytes())?;
+                tx_db.insert(format!("token_by_uid/{}", uid).as_bytes(), key.clone())?;
 
                 if let Some(old_token_data) = tx_db.insert(
-                    token.to_bytes(),
+                    key.clone(),
                     TokenData {
                         uid: uid.to_string(),
                         last_use: SystemTime::UNIX_EPOCH,
@@ -76,85 +310,535 @@ impl TokenDB {
         Ok(token)
     }
 
-    pub fn create_token_for_user(&self, uid: &str) -> Result<Token> {
-        self.add_token(Token::random()?, uid)
+    pub fn create_token_for_user(&self, uid: &str, token_bytes: usize) -> Result<Token> {
+        self.add_token(Token::random(token_bytes)?, uid)
     }
 
-    pub fn try_use_token(&self, token: Token, min_interval: Duration) -> Result<()> {
+    // Deletes a token and returns the UID it belonged to
+    pub fn revoke_token(&self, token: Token) -> Result<String> {
+        token.check()?;
+        let key = self.key_of(&token);
         self.db
             .transaction(|tx_db: &sled::transaction::TransactionalTree| {
-                let now = SystemTime::now();
-
                 let data = TokenData::try_from_buf(
                     tx_db
-                        .get(&token.to_bytes())?
+                        .remove(key.clone())?
                         .context("This token does not exist")
                         .map_err(to_abort)?
                         .as_ref(),
                 )
                 .map_err(to_abort)?;
 
-                let duration = now.duration_since(data.last_use).unwrap_or(Duration::ZERO);
-                if duration < min_interval {
+                // Tokens added by hand may not be the ones the index points to
+                let index_key = format!("token_by_uid/{}", data.uid);
+                let is_indexed = tx_db
+                    .get(index_key.as_bytes())?
+                    .is_some_and(|indexed| bool::from(indexed.as_ref().ct_eq(&key)));
+                if is_indexed {
+                    tx_db.remove(index_key.as_bytes())?;
+                }
+
+                Ok(data.uid)
+            })
+            .map_err(from_abort)
+    }
+
+    // Replaces the token of a user with a new one. The cooldown carries over to the new token
+    pub fn rotate_token_for_user(&self, uid: &str, token_bytes: usize) -> Result<Token> {
+        let new_token = Token::random(token_bytes)?;
+        let new_key = self.key_of(&new_token);
+        self.db
+            .transaction(|tx_db: &sled::transaction::TransactionalTree| {
+                let index_key = format!("token_by_uid/{}", uid);
+                let old_key = tx_db
+                    .get(index_key.as_bytes())?
+                    .with_context(|| format!("User {:?} does not have a token", uid))
+                    .map_err(to_abort)?;
+
+                let old_data = tx_db
+                    .remove(old_key)?
+                    .context("The token of this user does not exist")
+                    .map_err(to_abort)?;
+
+                if tx_db.insert(new_key.clone(), old_data)?.is_some() {
                     return Err(to_abort(anyhow!(
-                        "Cooldown period is {:?}, you have to wait {:?} more",
-                        min_interval,
-                        min_interval - duration
+                        "Generated token collides with an existing one, please try again"
                     )));
                 }
+                tx_db.insert(index_key.as_bytes(), new_key.clone())?;
 
-                tx_db.insert(
-                    token.to_bytes(),
-                    TokenData {
-                        uid: data.uid,
-                        last_use: now,
-                    }
-                    .try_to_buf()
-                    .map_err(to_abort)?,
-                )?;
+                Ok(())
+            })
+            .map_err(from_abort)?;
+        Ok(new_token)
+    }
+
+    pub fn list_tokens(&self) -> Result<Vec<TokenInfo>> {
+        let mut tokens = Vec::new();
+        for entry in self.db.scan_prefix([HASHED_TOKEN_PREFIX]) {
+            let (_, value) = entry?;
+            let data = TokenData::try_from_buf(value.as_ref())?;
+            tokens.push(TokenInfo {
+                status: self.user_status(&data.uid)?,
+                role: self.role(&data.uid)?,
+                uid: data.uid,
+                last_use: if data.last_use == SystemTime::UNIX_EPOCH {
+                    None
+                } else {
+                    Some(data.last_use)
+                },
+            });
+        }
+        Ok(tokens)
+    }
+
+    pub fn set_user_status(&self, uid: &str, status: UserStatus) -> Result<()> {
+        let key = format!("user_status/{}", uid);
+        match status {
+            UserStatus::Active => {
+                self.db.remove(key.as_bytes())?;
+            }
+            _ => {
+                self.db.insert(key.as_bytes(), status.to_buf())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn user_status(&self, uid: &str) -> Result<UserStatus> {
+        match self.db.get(format!("user_status/{}", uid).as_bytes())? {
+            Some(buf) => UserStatus::try_from_buf(buf.as_ref()),
+            None => Ok(UserStatus::Active),
+        }
+    }
+
+    pub fn set_role(&self, uid: &str, role: Option<Role>) -> Result<()> {
+        let key = format!("role/{}", uid);
+        match role {
+            Some(role) => {
+                self.db.insert(key.as_bytes(), role.name().as_bytes())?;
+            }
+            None => {
+                self.db.remove(key.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn role(&self, uid: &str) -> Result<Option<Role>> {
+        match self.db.get(format!("role/{}", uid).as_bytes())? {
+            Some(name) => {
+                let name = std::str::from_utf8(name.as_ref()).context("Invalid role")?;
+                Ok(Some(Role::from_name(name)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Returns the owner of a token without charging the cooldown, e.g. to authenticate an admin
+    pub fn uid_of_token(&self, token: &Token) -> Result<Option<String>> {
+        token.check()?;
+        match self.db.get(self.key_of(token))? {
+            Some(data) => Ok(Some(TokenData::try_from_buf(data.as_ref())?.uid)),
+            None => Ok(None),
+        }
+    }
+
+    // Charges the cooldown and journals the placement, whose UID is replaced with the owner of the
+    // token. Neither reaches the disk before the next flush()
+    pub fn try_use_token(
+        &self,
+        token: Token,
+        min_interval: Duration,
+        entry: &JournalEntry,
+    ) -> Result<TokenUse> {
+        // Mistyped tokens are rejected before touching the database
+        token.check()?;
+        let key = self.key_of(&token);
+        let token_use = self
+            .db
+            .transaction(|tx_db: &sled::transaction::TransactionalTree| {
+                use_token(tx_db, &key, min_interval, entry)
+            })
+            .map_err(from_abort)?;
+        Ok(token_use)
+    }
+
+    // Same as try_use_token, but for the current token of a user, e.g. one authenticated by a
+    // session cookie
+    pub fn try_use_token_of_user(
+        &self,
+        uid: &str,
+        min_interval: Duration,
+        entry: &JournalEntry,
+    ) -> Result<TokenUse> {
+        let token_use = self
+            .db
+            .transaction(|tx_db: &sled::transaction::TransactionalTree| {
+                let key = tx_db
+                    .get(format!("token_by_uid/{}", uid).as_bytes())?
+                    .context("You don't have a token, please log in again")
+                    .map_err(to_abort)?;
+                use_token(tx_db, &key, min_interval, entry)
+            })
+            .map_err(from_abort)?;
+        Ok(token_use)
+    }
+
+    // Waits until all changes are on disk
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush().context("Failed to flush token database")?;
+        Ok(())
+    }
+
+    // Returns the journaled placements in the order they were made
+    pub fn journal(&self) -> Result<Vec<(u64, JournalEntry)>> {
+        let mut entries = Vec::new();
+        for entry in self.db.scan_prefix(JOURNAL_PREFIX) {
+            let (key, value) = entry?;
+            entries.push((
+                journal_id_from_key(&key)?,
+                JournalEntry::try_from_buf(value.as_ref())?,
+            ));
+        }
+        Ok(entries)
+    }
+
+    // Called once the placement has reached the disk
+    pub fn remove_from_journal(&self, id: u64) -> Result<()> {
+        self.db.remove(journal_key(id))?;
+        Ok(())
+    }
 
+    // Undoes try_use_token for a placement that failed after the cooldown was charged: the token
+    // gets its previous last use back and the placement is removed from the journal
+    pub fn refund(&self, token_use: &TokenUse) -> Result<()> {
+        self.db
+            .transaction(|tx_db: &sled::transaction::TransactionalTree| {
+                // The token may have been rotated in the meantime, in which case the cooldown moved
+                // over to the new token, or revoked
+                let mut key = token_use.key.clone();
+                if tx_db.get(&key)?.is_none() {
+                    let index_key = format!("token_by_uid/{}", token_use.uid);
+                    if let Some(new_key) = tx_db.get(index_key.as_bytes())? {
+                        key = new_key.to_vec();
+                    }
+                }
+                if let Some(buf) = tx_db.get(&key)? {
+                    let data = TokenData::try_from_buf(buf.as_ref()).map_err(to_abort)?;
+                    tx_db.insert(
+                        key.clone(),
+                        TokenData {
+                            uid: data.uid,
+                            last_use: token_use.previous_last_use,
+                        }
+                        .try_to_buf()
+                        .map_err(to_abort)?,
+                    )?;
+                }
+                if let Some(id) = token_use.journal_id {
+                    tx_db.remove(journal_key(id))?;
+                }
                 Ok(())
             })
             .map_err(from_abort)?;
+        self.db.flush().context("Failed to flush token database")?;
         Ok(())
     }
+
+    // Drops all journaled placements, e.g. when the grid they were made on is replaced. Returns
+    // their number
+    pub fn clear_journal(&self) -> Result<usize> {
+        let mut count = 0;
+        for entry in self.db.scan_prefix(JOURNAL_PREFIX) {
+            self.db.remove(entry?.0)?;
+            count += 1;
+        }
+        self.db.flush()?;
+        Ok(count)
+    }
+}
+
+fn use_token(
+    tx_db: &sled::transaction::TransactionalTree,
+    key: &[u8],
+    min_interval: Duration,
+    entry: &JournalEntry,
+) -> sled::transaction::ConflictableTransactionResult<
+    TokenUse,
+    Box<dyn std::error::Error + Send + Sync + 'static>,
+> {
+    let now = SystemTime::now();
+
+    let data = TokenData::try_from_buf(
+        tx_db
+            .get(key)?
+            .context("This token does not exist")
+            .map_err(to_abort)?
+            .as_ref(),
+    )
+    .map_err(to_abort)?;
+
+    // Banned users are not charged the cooldown, so that they can place right after the ban ends
+    let status = match tx_db.get(format!("user_status/{}", data.uid).as_bytes())? {
+        Some(buf) => UserStatus::try_from_buf(buf.as_ref()).map_err(to_abort)?,
+        None => UserStatus::Active,
+    };
+    match status {
+        UserStatus::Banned(None) => {
+            return Err(to_abort(anyhow!("You are banned")));
+        }
+        UserStatus::Banned(Some(until)) if until > now => {
+            return Err(to_abort(anyhow!(
+                "You are banned, the ban ends in {:?}",
+                until.duration_since(now).unwrap_or(Duration::ZERO)
+            )));
+        }
+        _ => {}
+    }
+
+    let duration = now.duration_since(data.last_use).unwrap_or(Duration::ZERO);
+    if duration < min_interval {
+        return Err(to_abort(anyhow!(
+            "Cooldown period is {:?}, you have to wait {:?} more",
+            min_interval,
+            min_interval - duration
+        )));
+    }
+
+    tx_db.insert(
+        key,
+        TokenData {
+            uid: data.uid.clone(),
+            last_use: now,
+        }
+        .try_to_buf()
+        .map_err(to_abort)?,
+    )?;
+
+    let journal_id = if status == UserStatus::Shadowbanned {
+        None
+    } else {
+        let entry = JournalEntry {
+            uid: data.uid.clone(),
+            ..*entry
+        };
+        let id = tx_db.generate_id()?;
+        tx_db.insert(journal_key(id), entry.try_to_buf().map_err(to_abort)?)?;
+        Some(id)
+    };
+
+    Ok(TokenUse {
+        uid: data.uid,
+        journal_id,
+        key: key.to_vec(),
+        previous_last_use: data.last_use,
+    })
+}
+
+fn journal_key(id: u64) -> Vec<u8> {
+    let mut key = JOURNAL_PREFIX.to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn journal_id_from_key(key: &[u8]) -> Result<u64> {
+    let id = key
+        .strip_prefix(JOURNAL_PREFIX)
+        .and_then(|id| id.try_into().ok())
+        .context("Invalid journal key")?;
+    Ok(u64::from_be_bytes(id))
+}
+
+fn token_checksum(body: &str) -> String {
+    let digest = Sha256::digest(body.as_bytes());
+    let mut checksum = String::with_capacity(CHECKSUM_LEN);
+    for byte in &digest[..CHECKSUM_LEN / 2] {
+        write!(checksum, "{:02x}", byte).unwrap();
+    }
+    checksum
+}
+
+fn hash_token(key: &[u8], token: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    let mut hashed = Vec::with_capacity(33);
+    hashed.push(HASHED_TOKEN_PREFIX);
+    hashed.extend_from_slice(&mac.finalize().into_bytes());
+    hashed
+}
+
+// Copies a version 1 database to `new_path`, replacing raw tokens with their hashes. Returns the
+// number of tokens
+fn migrate_from_v1(db: &sled::Db, new_path: &Path, key: &[u8]) -> Result<usize> {
+    let new_db = sled::open(new_path).context("Failed to create new token database")?;
+    let mut count = 0;
+    for entry in db.iter() {
+        let (entry_key, value) = entry?;
+        if entry_key.first() == Some(&RAW_TOKEN_PREFIX) {
+            let token = std::str::from_utf8(&entry_key[1..]).context("Failed to parse token")?;
+            new_db.insert(hash_token(key, token), value)?;
+            count += 1;
+        } else if entry_key.starts_with(b"token_by_uid/")
+            && value.first() == Some(&RAW_TOKEN_PREFIX)
+        {
+            let token = std::str::from_utf8(&value[1..]).context("Failed to parse token")?;
+            new_db.insert(entry_key, hash_token(key, token))?;
+        } else {
+            new_db.insert(entry_key, value)?;
+        }
+    }
+    new_db.insert(VERSION_KEY, &VERSION.to_le_bytes())?;
+    new_db.flush()?;
+    Ok(count)
+}
+
+fn load_key(path: &Path, may_create: bool) -> Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(key) => return Ok(key),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && may_create => {}
+        Err(e) => return Err(e).context("Failed to read token database key"),
+    }
+
+    let mut key = vec![0u8; 32];
+    OsRng
+        .try_fill_bytes(&mut key)
+        .context("Failed to generate token database key")?;
+    // Only the owner may read the key
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&key))
+        .context("Failed to save token database key")?;
+    Ok(key)
 }
 
 impl Token {
-    fn random() -> Result<Token> {
-        let mut buf = [0u8; 8];
-        buf.try_fill(&mut rand::thread_rng())
+    fn random(bytes: usize) -> Result<Token> {
+        let mut buf = vec![0u8; bytes];
+        OsRng
+            .try_fill_bytes(&mut buf)
             .context("Failed to generate random token")?;
 
-        let mut s = String::with_capacity(buf.len() * 2);
+        let mut s = String::with_capacity(TOKEN_PREFIX.len() + buf.len() * 2 + CHECKSUM_LEN);
+        s.push_str(TOKEN_PREFIX);
         for byte in buf {
             write!(s, "{:02x}", byte).unwrap();
         }
+        let checksum = token_checksum(&s);
+        s.push_str(&checksum);
         Ok(Token(s))
     }
 
-    fn try_from_bytes(data: &[u8]) -> Result<Token> {
-        if data[0] != 0xff {
-            bail!("Invalid token format");
+    fn check(&self) -> Result<()> {
+        if !self.0.starts_with(TOKEN_PREFIX) {
+            return Ok(());
         }
-        Ok(Token(
-            String::from_utf8(data[1..].to_vec()).context("Failed to parse token")?,
-        ))
-    }
-
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut vec = Vec::with_capacity(1 + self.0.as_bytes().len());
-        vec.push(0xff);
-        vec.extend_from_slice(self.0.as_bytes());
-        vec
+        if self.0.len() < TOKEN_PREFIX.len() + CHECKSUM_LEN
+            || !self.0.is_char_boundary(self.0.len() - CHECKSUM_LEN)
+        {
+            bail!("This token is malformed, please check that it was copied correctly");
+        }
+        let (body, checksum) = self.0.split_at(self.0.len() - CHECKSUM_LEN);
+        if !bool::from(token_checksum(body).as_bytes().ct_eq(checksum.as_bytes())) {
+            bail!("This token is mistyped, please check that it was copied correctly");
+        }
+        Ok(())
     }
 
     pub fn from_string(s: &str) -> Token {
         Token(s.to_string())
     }
+}
 
-    pub fn to_string(&self) -> String {
-        self.0.clone()
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl UserStatus {
+    // Parses the status names used by the admin endpoints. Only bans take a time
+    pub fn parse(name: &str, until: Option<SystemTime>) -> Result<UserStatus> {
+        match (name, until) {
+            ("active", None) => Ok(UserStatus::Active),
+            ("banned", until) => Ok(UserStatus::Banned(until)),
+            ("shadowbanned", None) => Ok(UserStatus::Shadowbanned),
+            _ => bail!("Status must be one of 'active', 'banned', and 'shadowbanned'; 'until' is only allowed for bans"),
+        }
+    }
+
+    fn try_from_buf(buf: &[u8]) -> Result<UserStatus> {
+        if buf.len() != 9 {
+            bail!("Invalid user status");
+        }
+        let until = u64::from_le_bytes(buf[1..9].try_into().unwrap());
+        let until = SystemTime::UNIX_EPOCH + Duration::from_millis(until);
+        match buf[0] {
+            1 => Ok(UserStatus::Banned(None)),
+            3 => Ok(UserStatus::Banned(Some(until))),
+            2 => Ok(UserStatus::Shadowbanned),
+            kind => bail!("Unknown user status {}", kind),
+        }
+    }
+
+    // One byte for the kind of the status and the end of a temporary ban in milliseconds.
+    // Permanent bans have their own kind, so that a ban until the epoch doesn't turn into one
+    fn to_buf(self) -> Vec<u8> {
+        let (kind, until) = match self {
+            UserStatus::Active => (0u8, None),
+            UserStatus::Banned(None) => (1, None),
+            UserStatus::Shadowbanned => (2, None),
+            UserStatus::Banned(Some(until)) => (3, Some(until)),
+        };
+        let until = until.map_or(0, |until| {
+            let millis = until
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_millis();
+            u64::try_from(millis).unwrap_or(u64::MAX)
+        });
+        let mut buf = Vec::with_capacity(9);
+        buf.push(kind);
+        buf.extend_from_slice(&until.to_le_bytes());
+        buf
+    }
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Role> {
+        match name {
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => bail!("Unknown role {:?}: must be 'moderator' or 'admin'", name),
+        }
+    }
+}
+
+impl fmt::Display for UserStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserStatus::Active => write!(f, "active"),
+            UserStatus::Banned(None) => write!(f, "banned"),
+            UserStatus::Banned(Some(until)) => write!(
+                f,
+                "banned until {}",
+                until
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO)
+                    .as_secs()
+            ),
+            UserStatus::Shadowbanned => write!(f, "shadowbanned"),
+        }
     }
 }
 
@@ -170,8 +854,7 @@ impl TokenData {
                 let last_use_timestamp = u64::from_le_bytes(buf[4..12].try_into().unwrap());
                 let last_use = SystemTime::UNIX_EPOCH + Duration::from_millis(last_use_timestamp);
 
-                let uid =
-                    String::from_utf8((&buf[12..]).to_vec()).context("Failed to parse UID")?;
+                let uid = String::from_utf8(buf[12..].to_vec()).context("Failed to parse UID")?;
 
                 Ok(TokenData { uid, last_use })
             }
@@ -182,15 +865,123 @@ impl TokenData {
     fn try_to_buf(&self) -> Result<Vec<u8>> {
         let uid = self.uid.as_bytes();
         let mut data = Vec::with_capacity(12 + uid.len());
-        data.write(&1u32.to_le_bytes())?;
-        data.write(
+        data.write_all(&1u32.to_le_bytes())?;
+        data.write_all(
             &(self
                 .last_use
                 .duration_since(SystemTime::UNIX_EPOCH)?
                 .as_millis() as u64)
                 .to_le_bytes(),
         )?;
-        data.write(uid)?;
+        data.write_all(uid)?;
         Ok(data)
     }
 }
+
+impl JournalEntry {
+    fn try_from_buf(buf: &[u8]) -> Result<JournalEntry> {
+        if buf.len() < 20 {
+            bail!("Journal entry is truncated");
+        }
+
+        let timestamp = u64::from_le_bytes(buf[..8].try_into().unwrap());
+        let x = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        let y = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        let color = CellData {
+            r: buf[16],
+            g: buf[17],
+            b: buf[18],
+            a: buf[19],
+        };
+        let uid = String::from_utf8(buf[20..].to_vec()).context("Failed to parse UID")?;
+
+        Ok(JournalEntry {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp),
+            uid,
+            x,
+            y,
+            color,
+        })
+    }
+
+    fn try_to_buf(&self) -> Result<Vec<u8>> {
+        let uid = self.uid.as_bytes();
+        let mut data = Vec::with_capacity(20 + uid.len());
+        data.write_all(
+            &(self
+                .timestamp
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis() as u64)
+                .to_le_bytes(),
+        )?;
+        data.write_all(&self.x.to_le_bytes())?;
+        data.write_all(&self.y.to_le_bytes())?;
+        data.write_all(&[self.color.r, self.color.g, self.color.b, self.color.a])?;
+        data.write_all(uid)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(status: UserStatus) -> UserStatus {
+        UserStatus::try_from_buf(&status.to_buf()).unwrap()
+    }
+
+    #[test]
+    fn user_status_round_trips() {
+        let until = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        // Active users have no stored status
+        for status in [
+            UserStatus::Banned(None),
+            UserStatus::Banned(Some(until)),
+            UserStatus::Shadowbanned,
+        ] {
+            assert!(round_trip(status) == status);
+        }
+    }
+
+    #[test]
+    fn ban_until_epoch_is_not_permanent() {
+        let status = UserStatus::Banned(Some(SystemTime::UNIX_EPOCH));
+        assert!(round_trip(status) == status);
+    }
+
+    fn entry() -> JournalEntry {
+        JournalEntry {
+            timestamp: SystemTime::now(),
+            uid: String::new(),
+            x: 1,
+            y: 2,
+            color: CellData {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 255,
+            },
+        }
+    }
+
+    #[test]
+    fn refund_follows_rotated_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokendb = TokenDB::open(&dir.path().join("tokendb")).unwrap();
+        let token = tokendb.create_token_for_user("alice", 16).unwrap();
+        let cooldown = Duration::from_secs(60);
+
+        let token_use = tokendb.try_use_token(token, cooldown, &entry()).unwrap();
+        assert_eq!(tokendb.journal().unwrap().len(), 1);
+        let new_token = tokendb.rotate_token_for_user("alice", 16).unwrap();
+        assert!(tokendb
+            .try_use_token(Token(new_token.0.clone()), cooldown, &entry())
+            .is_err());
+
+        tokendb.refund(&token_use).unwrap();
+        assert!(tokendb.journal().unwrap().is_empty());
+        tokendb
+            .try_use_token(new_token, cooldown, &entry())
+            .unwrap();
+    }
+}
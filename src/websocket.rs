@@ -0,0 +1,107 @@
+use rocket::data::{IoHandler, IoStream};
+use rocket::futures::future::BoxFuture;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use std::io;
+use std::pin::Pin;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::Role;
+
+type UpgradeHandler = Box<dyn FnOnce(WebSocketStream<IoStream>) -> BoxFuture<'static, ()> + Send>;
+
+// Request guard for websocket handshake requests
+pub struct Handshake {
+    key: String,
+    requested_protocols: Option<String>,
+}
+
+// Responder that completes the handshake and hands the upgraded connection over to a callback
+pub struct Upgrade {
+    accept_key: String,
+    protocol: Option<&'static str>,
+    handler: UpgradeHandler,
+}
+
+struct UpgradeIoHandler(UpgradeHandler);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Handshake {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        let is_upgrade = headers
+            .get("Connection")
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+        let is_websocket = headers
+            .get_one("Upgrade")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("websocket"));
+        if !is_upgrade || !is_websocket {
+            return Outcome::Error((Status::UpgradeRequired, "Expected a websocket handshake"));
+        }
+
+        if headers.get_one("Sec-WebSocket-Version") != Some("13") {
+            return Outcome::Error((Status::BadRequest, "Unsupported websocket version"));
+        }
+
+        let key = match headers.get_one("Sec-WebSocket-Key") {
+            Some(key) => key.to_string(),
+            None => return Outcome::Error((Status::BadRequest, "Missing websocket key")),
+        };
+
+        let requested_protocols = headers.get("Sec-WebSocket-Protocol").collect::<Vec<_>>();
+        let requested_protocols = if requested_protocols.is_empty() {
+            None
+        } else {
+            Some(requested_protocols.join(","))
+        };
+
+        Outcome::Success(Handshake {
+            key,
+            requested_protocols,
+        })
+    }
+}
+
+impl Handshake {
+    pub fn requested_protocols(&self) -> Option<&str> {
+        self.requested_protocols.as_deref()
+    }
+
+    // Accepts the connection, confirming `protocol` as the subprotocol if set
+    pub fn accept<F>(self, protocol: Option<&'static str>, handler: F) -> Upgrade
+    where
+        F: FnOnce(WebSocketStream<IoStream>) -> BoxFuture<'static, ()> + Send + 'static,
+    {
+        Upgrade {
+            accept_key: tungstenite::handshake::derive_accept_key(self.key.as_bytes()),
+            protocol,
+            handler: Box::new(handler),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Upgrade {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.raw_header("Sec-WebSocket-Accept", self.accept_key);
+        if let Some(protocol) = self.protocol {
+            response.raw_header("Sec-WebSocket-Protocol", protocol);
+        }
+        response
+            .upgrade("websocket", UpgradeIoHandler(self.handler))
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for UpgradeIoHandler {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let ws_stream = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        (Pin::into_inner(self).0)(ws_stream).await;
+        Ok(())
+    }
+}